@@ -0,0 +1,306 @@
+use std::cmp;
+use std::ptr;
+use vk;
+use device::Device;
+
+#[derive(Debug)]
+pub enum AllocError {
+    /// None of the memory types allowed by `memory_type_bits` has the requested properties.
+    NoCompatibleMemoryType,
+    VkError(vk::Result),
+}
+
+/// Buffers and linearly tiled images must not share a `buffer_image_granularity` sized page
+/// with optimally tiled images. The allocator never places both kinds in the same block, so
+/// the granularity never has to be padded for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    Linear,
+    Optimal,
+}
+
+/// A range of a `vk::DeviceMemory` block handed out by an `Allocator`. It has to be returned
+/// with `Allocator::free`.
+#[derive(Debug)]
+pub struct Allocation {
+    memory: vk::DeviceMemory,
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+    memory_type_index: vk::uint32_t,
+    kind: ResourceKind,
+}
+
+impl Allocation {
+    pub fn memory(&self) -> vk::DeviceMemory {
+        self.memory
+    }
+
+    pub fn offset(&self) -> vk::DeviceSize {
+        self.offset
+    }
+
+    pub fn size(&self) -> vk::DeviceSize {
+        self.size
+    }
+
+    pub fn memory_type_index(&self) -> vk::uint32_t {
+        self.memory_type_index
+    }
+
+    pub fn kind(&self) -> ResourceKind {
+        self.kind
+    }
+}
+
+struct Block {
+    memory: vk::DeviceMemory,
+    memory_type_index: vk::uint32_t,
+    kind: ResourceKind,
+    // Sorted by offset, adjacent ranges are always merged.
+    free: Vec<(vk::DeviceSize, vk::DeviceSize)>,
+    allocation_count: usize,
+}
+
+impl Block {
+    fn allocate(&mut self,
+                size: vk::DeviceSize,
+                alignment: vk::DeviceSize)
+                -> Option<vk::DeviceSize> {
+        let alignment = cmp::max(alignment, 1);
+        let position = self.free.iter().position(|&(offset, free_size)| {
+            let aligned = align_up(offset, alignment);
+            aligned + size <= offset + free_size
+        });
+        position.map(|index| {
+            let (offset, free_size) = self.free.remove(index);
+            let aligned = align_up(offset, alignment);
+            let end = aligned + size;
+            if end < offset + free_size {
+                self.free.insert(index, (end, offset + free_size - end));
+            }
+            if aligned > offset {
+                self.free.insert(index, (offset, aligned - offset));
+            }
+            self.allocation_count += 1;
+            aligned
+        })
+    }
+
+    fn free(&mut self, offset: vk::DeviceSize, size: vk::DeviceSize) {
+        let index = self.free
+            .iter()
+            .position(|&(free_offset, _)| free_offset > offset)
+            .unwrap_or(self.free.len());
+        self.free.insert(index, (offset, size));
+        if index + 1 < self.free.len() {
+            let (next_offset, next_size) = self.free[index + 1];
+            if offset + size == next_offset {
+                self.free[index].1 += next_size;
+                self.free.remove(index + 1);
+            }
+        }
+        if index > 0 {
+            let (prev_offset, prev_size) = self.free[index - 1];
+            if prev_offset + prev_size == offset {
+                self.free[index - 1].1 += self.free[index].1;
+                self.free.remove(index);
+            }
+        }
+        self.allocation_count -= 1;
+    }
+}
+
+/// Sub-allocates buffers and images out of large `vk::DeviceMemory` blocks, so that the number
+/// of allocations stays well below `PhysicalDeviceLimits::max_memory_allocation_count`.
+///
+/// Blocks are `block_size` bytes large, requests that don't fit into a block get a dedicated
+/// allocation. A block is returned to the device as soon as its last allocation is freed.
+pub struct Allocator {
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    block_size: vk::DeviceSize,
+    blocks: Vec<Block>,
+}
+
+impl Allocator {
+    /// `memory_properties` comes from `Instance::get_physical_device_memory_properties`.
+    pub fn new(memory_properties: vk::PhysicalDeviceMemoryProperties,
+               block_size: vk::DeviceSize)
+               -> Allocator {
+        Allocator {
+            memory_properties,
+            block_size,
+            blocks: Vec::new(),
+        }
+    }
+
+    /// Number of `vk::DeviceMemory` objects that are currently allocated.
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn allocate(&mut self,
+                    device: &Device,
+                    requirements: &vk::MemoryRequirements,
+                    property_flags: vk::MemoryPropertyFlags,
+                    kind: ResourceKind)
+                    -> Result<Allocation, AllocError> {
+        let memory_type_index = self.memory_properties
+            .find_memory_type(requirements.memory_type_bits,
+                              property_flags,
+                              vk::MemoryPropertyFlags::empty())
+            .ok_or(AllocError::NoCompatibleMemoryType)?
+            .index;
+        for block in self.blocks.iter_mut() {
+            if block.memory_type_index != memory_type_index || block.kind != kind {
+                continue;
+            }
+            if let Some(offset) = block.allocate(requirements.size, requirements.alignment) {
+                return Ok(Allocation {
+                    memory: block.memory,
+                    offset,
+                    size: requirements.size,
+                    memory_type_index,
+                    kind,
+                });
+            }
+        }
+        let block_size = cmp::max(self.block_size, requirements.size);
+        let allocate_info = vk::MemoryAllocateInfo {
+            s_type: vk::StructureType::MemoryAllocateInfo,
+            p_next: ptr::null(),
+            allocation_size: block_size,
+            memory_type_index,
+        };
+        let memory = device.allocate_memory(&allocate_info).map_err(AllocError::VkError)?;
+        let mut block = Block {
+            memory,
+            memory_type_index,
+            kind,
+            free: vec![(0, block_size)],
+            allocation_count: 0,
+        };
+        let offset = block.allocate(requirements.size, requirements.alignment)
+            .expect("A new block always fits the allocation");
+        self.blocks.push(block);
+        Ok(Allocation {
+            memory,
+            offset,
+            size: requirements.size,
+            memory_type_index,
+            kind,
+        })
+    }
+
+    /// Allocates memory for `buffer` and binds it with `bind_buffer_memory`.
+    pub fn allocate_buffer(&mut self,
+                           device: &Device,
+                           buffer: vk::Buffer,
+                           property_flags: vk::MemoryPropertyFlags)
+                           -> Result<Allocation, AllocError> {
+        let requirements = device.get_buffer_memory_requirements(buffer);
+        let allocation =
+            self.allocate(device, &requirements, property_flags, ResourceKind::Linear)?;
+        if let Err(err) = device.bind_buffer_memory(buffer, allocation.memory, allocation.offset) {
+            self.free(device, allocation);
+            return Err(AllocError::VkError(err));
+        }
+        Ok(allocation)
+    }
+
+    /// Allocates memory for `image` and binds it with `bind_image_memory`. `tiling` has to
+    /// be the tiling the image was created with.
+    pub fn allocate_image(&mut self,
+                          device: &Device,
+                          image: vk::Image,
+                          tiling: vk::ImageTiling,
+                          property_flags: vk::MemoryPropertyFlags)
+                          -> Result<Allocation, AllocError> {
+        let requirements = device.get_image_memory_requirements(image);
+        let kind = match tiling {
+            vk::ImageTiling::Linear => ResourceKind::Linear,
+            vk::ImageTiling::Optimal => ResourceKind::Optimal,
+        };
+        let allocation = self.allocate(device, &requirements, property_flags, kind)?;
+        if let Err(err) = device.bind_image_memory(image, allocation.memory, allocation.offset) {
+            self.free(device, allocation);
+            return Err(AllocError::VkError(err));
+        }
+        Ok(allocation)
+    }
+
+    pub fn free(&mut self, device: &Device, allocation: Allocation) {
+        let index = self.blocks
+            .iter()
+            .position(|block| block.memory == allocation.memory)
+            .expect("Allocation was not made by this allocator");
+        self.blocks[index].free(allocation.offset, allocation.size);
+        if self.blocks[index].allocation_count == 0 {
+            let block = self.blocks.swap_remove(index);
+            device.free_memory(block.memory);
+        }
+    }
+
+    /// Frees every block, any `Allocation` that is still alive becomes dangling.
+    pub fn destroy(&mut self, device: &Device) {
+        for block in self.blocks.drain(..) {
+            device.free_memory(block.memory);
+        }
+    }
+}
+
+fn align_up(offset: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    offset.div_ceil(alignment) * alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use vk;
+    use super::{Block, ResourceKind};
+
+    fn block(size: vk::DeviceSize) -> Block {
+        Block {
+            memory: vk::DeviceMemory::null(),
+            memory_type_index: 0,
+            kind: ResourceKind::Linear,
+            free: vec![(0, size)],
+            allocation_count: 0,
+        }
+    }
+
+    #[test]
+    fn alignment_padding_stays_free() {
+        let mut block = block(256);
+        assert_eq!(block.allocate(4, 1), Some(0));
+        assert_eq!(block.allocate(16, 64), Some(64));
+        assert_eq!(block.free, vec![(4, 60), (80, 176)]);
+        // The padding is used by a later allocation that fits into it.
+        assert_eq!(block.allocate(32, 16), Some(16));
+        assert_eq!(block.free, vec![(4, 12), (48, 16), (80, 176)]);
+    }
+
+    #[test]
+    fn free_merges_with_both_neighbours() {
+        let mut block = block(96);
+        let first = block.allocate(32, 1).unwrap();
+        let second = block.allocate(32, 1).unwrap();
+        let third = block.allocate(32, 1).unwrap();
+        block.free(first, 32);
+        block.free(third, 32);
+        assert_eq!(block.free, vec![(0, 32), (64, 32)]);
+        block.free(second, 32);
+        assert_eq!(block.free, vec![(0, 96)]);
+        assert_eq!(block.allocation_count, 0);
+    }
+
+    #[test]
+    fn exhausted_block_fails() {
+        let mut block = block(64);
+        assert_eq!(block.allocate(48, 1), Some(0));
+        assert_eq!(block.allocate(32, 1), None);
+        // 16 bytes are left, but not at an offset aligned to 32.
+        assert_eq!(block.allocate(16, 32), None);
+        assert_eq!(block.allocate(16, 16), Some(48));
+        assert_eq!(block.allocate(1, 1), None);
+        assert!(block.free.is_empty());
+    }
+}
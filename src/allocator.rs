@@ -0,0 +1,275 @@
+use std::cmp;
+use std::fmt;
+use std::mem;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::alloc::{self, Layout};
+use vk;
+
+/// Host memory allocator used by the driver for everything it allocates on behalf of an
+/// instance or device. Must be safe to call from any thread, the driver can call it from every
+/// thread that calls into Vulkan.
+pub trait HostAllocator: Send + Sync {
+    /// Returns null if the allocation fails.
+    fn allocate(&self,
+                size: usize,
+                alignment: usize,
+                scope: vk::SystemAllocationScope)
+                -> *mut u8;
+
+    /// `original` is never null and `size` never 0, these cases are turned into `allocate` and
+    /// `free`. Returns null if the allocation fails, `original` stays valid in that case.
+    ///
+    /// # Safety
+    ///
+    /// `original` has to be a live allocation returned by this allocator.
+    unsafe fn reallocate(&self,
+                         original: *mut u8,
+                         size: usize,
+                         alignment: usize,
+                         scope: vk::SystemAllocationScope)
+                         -> *mut u8;
+
+    /// `memory` is never null.
+    ///
+    /// # Safety
+    ///
+    /// `memory` has to be a live allocation returned by this allocator.
+    unsafe fn free(&self, memory: *mut u8);
+
+    /// Called when the driver allocated memory that is not managed by this allocator.
+    fn internal_allocation(&self,
+                           _size: usize,
+                           _allocation_type: vk::InternalAllocationType,
+                           _scope: vk::SystemAllocationScope) {
+    }
+
+    fn internal_free(&self,
+                     _size: usize,
+                     _allocation_type: vk::InternalAllocationType,
+                     _scope: vk::SystemAllocationScope) {
+    }
+}
+
+/// Adapts a `HostAllocator` to `vk::AllocationCallbacks`. The callbacks stay valid for as long
+/// as this is alive.
+pub struct AllocationCallbacks {
+    // Boxed so that the address stored in `p_user_data` doesn't move.
+    allocator: Box<Arc<dyn HostAllocator>>,
+    callbacks: vk::AllocationCallbacks,
+}
+
+unsafe impl Send for AllocationCallbacks {}
+unsafe impl Sync for AllocationCallbacks {}
+
+impl AllocationCallbacks {
+    pub fn new(allocator: Arc<dyn HostAllocator>) -> AllocationCallbacks {
+        let mut allocator = Box::new(allocator);
+        let callbacks = vk::AllocationCallbacks {
+            p_user_data: &mut *allocator as *mut Arc<dyn HostAllocator> as *mut vk::c_void,
+            pfn_allocation: allocation_function,
+            pfn_reallocation: reallocation_function,
+            pfn_free: free_function,
+            pfn_internal_allocation: internal_allocation_notification,
+            pfn_internal_free: internal_free_notification,
+        };
+        AllocationCallbacks {
+            allocator,
+            callbacks,
+        }
+    }
+
+    pub fn allocator(&self) -> &Arc<dyn HostAllocator> {
+        &self.allocator
+    }
+
+    pub fn as_raw(&self) -> &vk::AllocationCallbacks {
+        &self.callbacks
+    }
+}
+
+impl fmt::Debug for AllocationCallbacks {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("AllocationCallbacks")
+            .field("callbacks", &self.callbacks)
+            .finish()
+    }
+}
+
+/// Returns the pointer that is passed as `p_allocator` to Vulkan.
+pub fn as_ptr(callbacks: &Option<Arc<AllocationCallbacks>>) -> *const vk::AllocationCallbacks {
+    match *callbacks {
+        Some(ref callbacks) => callbacks.as_raw(),
+        None => ptr::null(),
+    }
+}
+
+unsafe fn allocator_from_user_data<'a>(user_data: *mut vk::c_void) -> &'a Arc<dyn HostAllocator> {
+    &*(user_data as *const Arc<dyn HostAllocator>)
+}
+
+unsafe extern "system" fn allocation_function(user_data: *mut vk::c_void,
+                                              size: vk::size_t,
+                                              alignment: vk::size_t,
+                                              scope: vk::SystemAllocationScope)
+                                              -> *mut vk::c_void {
+    allocator_from_user_data(user_data).allocate(size, alignment, scope) as *mut vk::c_void
+}
+
+unsafe extern "system" fn reallocation_function(user_data: *mut vk::c_void,
+                                                original: *mut vk::c_void,
+                                                size: vk::size_t,
+                                                alignment: vk::size_t,
+                                                scope: vk::SystemAllocationScope)
+                                                -> *mut vk::c_void {
+    let allocator = allocator_from_user_data(user_data);
+    if original.is_null() {
+        return allocator.allocate(size, alignment, scope) as *mut vk::c_void;
+    }
+    if size == 0 {
+        allocator.free(original as *mut u8);
+        return ptr::null_mut();
+    }
+    allocator.reallocate(original as *mut u8, size, alignment, scope) as *mut vk::c_void
+}
+
+unsafe extern "system" fn free_function(user_data: *mut vk::c_void, memory: *mut vk::c_void) {
+    if !memory.is_null() {
+        allocator_from_user_data(user_data).free(memory as *mut u8);
+    }
+}
+
+unsafe extern "system" fn internal_allocation_notification
+    (user_data: *mut vk::c_void,
+     size: vk::size_t,
+     allocation_type: vk::InternalAllocationType,
+     scope: vk::SystemAllocationScope) {
+    allocator_from_user_data(user_data).internal_allocation(size, allocation_type, scope);
+}
+
+unsafe extern "system" fn internal_free_notification(user_data: *mut vk::c_void,
+                                                     size: vk::size_t,
+                                                     allocation_type: vk::InternalAllocationType,
+                                                     scope: vk::SystemAllocationScope) {
+    allocator_from_user_data(user_data).internal_free(size, allocation_type, scope);
+}
+
+// Stored in front of every allocation of the `TrackingAllocator`, so that `free` knows the
+// layout and the scope to account the bytes to.
+struct Header {
+    layout: Layout,
+    size: usize,
+    scope: vk::SystemAllocationScope,
+}
+
+const SCOPE_COUNT: usize = 5;
+
+/// A `HostAllocator` on top of the global Rust allocator that counts the bytes currently
+/// allocated per `SystemAllocationScope`, as well as the internal allocations the driver
+/// reports.
+#[derive(Debug, Default)]
+pub struct TrackingAllocator {
+    allocated: [AtomicUsize; SCOPE_COUNT],
+    internal_allocated: [AtomicUsize; SCOPE_COUNT],
+}
+
+impl TrackingAllocator {
+    pub fn new() -> TrackingAllocator {
+        TrackingAllocator::default()
+    }
+
+    /// Bytes currently allocated through this allocator in `scope`.
+    pub fn allocated(&self, scope: vk::SystemAllocationScope) -> usize {
+        self.allocated[scope as usize].load(Ordering::Relaxed)
+    }
+
+    /// Bytes the driver reported as allocated internally in `scope`.
+    pub fn internal_allocated(&self, scope: vk::SystemAllocationScope) -> usize {
+        self.internal_allocated[scope as usize].load(Ordering::Relaxed)
+    }
+
+    /// Bytes allocated through this allocator over all scopes.
+    pub fn total_allocated(&self) -> usize {
+        self.allocated.iter().map(|bytes| bytes.load(Ordering::Relaxed)).sum()
+    }
+
+    fn header_offset(alignment: usize) -> usize {
+        let alignment = cmp::max(alignment, mem::align_of::<Header>());
+        mem::size_of::<Header>().div_ceil(alignment) * alignment
+    }
+
+    unsafe fn header<'a>(memory: *mut u8) -> &'a mut Header {
+        &mut *(memory.offset(-(mem::size_of::<Header>() as isize)) as *mut Header)
+    }
+}
+
+impl HostAllocator for TrackingAllocator {
+    fn allocate(&self,
+                size: usize,
+                alignment: usize,
+                scope: vk::SystemAllocationScope)
+                -> *mut u8 {
+        let offset = TrackingAllocator::header_offset(alignment);
+        let alignment = cmp::max(alignment, mem::align_of::<Header>());
+        let layout = match Layout::from_size_align(offset + size, alignment) {
+            Ok(layout) => layout,
+            Err(_) => return ptr::null_mut(),
+        };
+        unsafe {
+            let base = alloc::alloc(layout);
+            if base.is_null() {
+                return ptr::null_mut();
+            }
+            let memory = base.add(offset);
+            ptr::write(memory.offset(-(mem::size_of::<Header>() as isize)) as *mut Header,
+                       Header {
+                           layout,
+                           size,
+                           scope,
+                       });
+            self.allocated[scope as usize].fetch_add(size, Ordering::Relaxed);
+            memory
+        }
+    }
+
+    unsafe fn reallocate(&self,
+                         original: *mut u8,
+                         size: usize,
+                         alignment: usize,
+                         scope: vk::SystemAllocationScope)
+                         -> *mut u8 {
+        let memory = self.allocate(size, alignment, scope);
+        if memory.is_null() {
+            return memory;
+        }
+        let original_size = TrackingAllocator::header(original).size;
+        ptr::copy_nonoverlapping(original, memory, cmp::min(original_size, size));
+        self.free(original);
+        memory
+    }
+
+    unsafe fn free(&self, memory: *mut u8) {
+        let (layout, size, scope) = {
+            let header = TrackingAllocator::header(memory);
+            (header.layout, header.size, header.scope)
+        };
+        self.allocated[scope as usize].fetch_sub(size, Ordering::Relaxed);
+        let offset = TrackingAllocator::header_offset(layout.align());
+        alloc::dealloc(memory.sub(offset), layout);
+    }
+
+    fn internal_allocation(&self,
+                           size: usize,
+                           _allocation_type: vk::InternalAllocationType,
+                           scope: vk::SystemAllocationScope) {
+        self.internal_allocated[scope as usize].fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn internal_free(&self,
+                     size: usize,
+                     _allocation_type: vk::InternalAllocationType,
+                     scope: vk::SystemAllocationScope) {
+        self.internal_allocated[scope as usize].fetch_sub(size, Ordering::Relaxed);
+    }
+}
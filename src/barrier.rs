@@ -0,0 +1,582 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::ptr;
+use vk;
+use device::Device;
+
+#[derive(Debug)]
+pub enum TrackerError {
+    UnknownImage(vk::Image),
+    UnknownBuffer(vk::Buffer),
+}
+
+/// How a resource is about to be used. Usages that take a stage apply to shaders of that
+/// stage, e.g. `PIPELINE_STAGE_FRAGMENT_SHADER_BIT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Usage {
+    TransferSrc,
+    TransferDst,
+    Sampled(vk::PipelineStageFlags),
+    StorageRead(vk::PipelineStageFlags),
+    StorageWrite(vk::PipelineStageFlags),
+    StorageReadWrite(vk::PipelineStageFlags),
+    /// Only applies to buffers.
+    UniformRead(vk::PipelineStageFlags),
+    /// Only applies to buffers.
+    VertexBuffer,
+    /// Only applies to buffers.
+    IndexBuffer,
+    /// Only applies to buffers.
+    IndirectBuffer,
+    ColorAttachment,
+    DepthStencilAttachment,
+    DepthStencilReadOnly,
+    InputAttachment,
+    Present,
+    HostRead,
+    HostWrite,
+}
+
+impl Usage {
+    pub fn stages(self) -> vk::PipelineStageFlags {
+        match self {
+            Usage::TransferSrc | Usage::TransferDst => vk::PIPELINE_STAGE_TRANSFER_BIT,
+            Usage::Sampled(stages) |
+            Usage::StorageRead(stages) |
+            Usage::StorageWrite(stages) |
+            Usage::StorageReadWrite(stages) |
+            Usage::UniformRead(stages) => stages,
+            Usage::VertexBuffer | Usage::IndexBuffer => vk::PIPELINE_STAGE_VERTEX_INPUT_BIT,
+            Usage::IndirectBuffer => vk::PIPELINE_STAGE_DRAW_INDIRECT_BIT,
+            Usage::ColorAttachment => vk::PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT,
+            Usage::DepthStencilAttachment |
+            Usage::DepthStencilReadOnly => {
+                vk::PIPELINE_STAGE_EARLY_FRAGMENT_TESTS_BIT |
+                vk::PIPELINE_STAGE_LATE_FRAGMENT_TESTS_BIT
+            }
+            Usage::InputAttachment => vk::PIPELINE_STAGE_FRAGMENT_SHADER_BIT,
+            Usage::Present => vk::PIPELINE_STAGE_BOTTOM_OF_PIPE_BIT,
+            Usage::HostRead | Usage::HostWrite => vk::PIPELINE_STAGE_HOST_BIT,
+        }
+    }
+
+    pub fn access(self) -> vk::AccessFlags {
+        match self {
+            Usage::TransferSrc => vk::ACCESS_TRANSFER_READ_BIT,
+            Usage::TransferDst => vk::ACCESS_TRANSFER_WRITE_BIT,
+            Usage::Sampled(_) | Usage::StorageRead(_) => vk::ACCESS_SHADER_READ_BIT,
+            Usage::StorageWrite(_) => vk::ACCESS_SHADER_WRITE_BIT,
+            Usage::StorageReadWrite(_) => vk::ACCESS_SHADER_READ_BIT | vk::ACCESS_SHADER_WRITE_BIT,
+            Usage::UniformRead(_) => vk::ACCESS_UNIFORM_READ_BIT,
+            Usage::VertexBuffer => vk::ACCESS_VERTEX_ATTRIBUTE_READ_BIT,
+            Usage::IndexBuffer => vk::ACCESS_INDEX_READ_BIT,
+            Usage::IndirectBuffer => vk::ACCESS_INDIRECT_COMMAND_READ_BIT,
+            Usage::ColorAttachment => {
+                vk::ACCESS_COLOR_ATTACHMENT_READ_BIT | vk::ACCESS_COLOR_ATTACHMENT_WRITE_BIT
+            }
+            Usage::DepthStencilAttachment => {
+                vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_READ_BIT |
+                vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_WRITE_BIT
+            }
+            Usage::DepthStencilReadOnly => vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_READ_BIT,
+            Usage::InputAttachment => vk::ACCESS_INPUT_ATTACHMENT_READ_BIT,
+            // The presentation engine makes the image visible itself.
+            Usage::Present => vk::AccessFlags::empty(),
+            Usage::HostRead => vk::ACCESS_HOST_READ_BIT,
+            Usage::HostWrite => vk::ACCESS_HOST_WRITE_BIT,
+        }
+    }
+
+    /// The layout an image needs for this usage, `None` for usages that only apply to buffers.
+    pub fn layout(self) -> Option<vk::ImageLayout> {
+        match self {
+            Usage::TransferSrc => Some(vk::ImageLayout::TransferSrcOptimal),
+            Usage::TransferDst => Some(vk::ImageLayout::TransferDstOptimal),
+            Usage::Sampled(_) | Usage::InputAttachment => {
+                Some(vk::ImageLayout::ShaderReadOnlyOptimal)
+            }
+            Usage::StorageRead(_) |
+            Usage::StorageWrite(_) |
+            Usage::StorageReadWrite(_) |
+            Usage::HostRead |
+            Usage::HostWrite => Some(vk::ImageLayout::General),
+            Usage::UniformRead(_) |
+            Usage::VertexBuffer |
+            Usage::IndexBuffer |
+            Usage::IndirectBuffer => None,
+            Usage::ColorAttachment => Some(vk::ImageLayout::ColorAttachmentOptimal),
+            Usage::DepthStencilAttachment => Some(vk::ImageLayout::DepthStencilAttachmentOptimal),
+            Usage::DepthStencilReadOnly => Some(vk::ImageLayout::DepthStencilReadOnlyOptimal),
+            Usage::Present => Some(vk::ImageLayout::PresentSrcKhr),
+        }
+    }
+}
+
+fn write_access() -> vk::AccessFlags {
+    vk::ACCESS_SHADER_WRITE_BIT | vk::ACCESS_COLOR_ATTACHMENT_WRITE_BIT |
+    vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_WRITE_BIT | vk::ACCESS_TRANSFER_WRITE_BIT |
+    vk::ACCESS_HOST_WRITE_BIT | vk::ACCESS_MEMORY_WRITE_BIT
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct State {
+    layout: vk::ImageLayout,
+    // The last write, or layout transition, that later accesses have to wait for.
+    write_stages: vk::PipelineStageFlags,
+    write_access: vk::AccessFlags,
+    // The reads that already wait for the last write.
+    read_stages: vk::PipelineStageFlags,
+    read_access: vk::AccessFlags,
+}
+
+impl State {
+    fn new(layout: vk::ImageLayout) -> State {
+        State {
+            layout,
+            write_stages: vk::PipelineStageFlags::empty(),
+            write_access: vk::AccessFlags::empty(),
+            read_stages: vk::PipelineStageFlags::empty(),
+            read_access: vk::AccessFlags::empty(),
+        }
+    }
+
+    /// Moves an image subresource to `usage` and returns the dependency that is needed for it,
+    /// if any.
+    fn transition(&mut self, usage: Usage) -> Option<Dependency> {
+        let new_layout = usage.layout().unwrap_or(self.layout);
+        self.transition_to(usage, new_layout)
+    }
+
+    /// Like `transition` for a buffer range, which has no layout.
+    fn transition_buffer(&mut self, usage: Usage) -> Option<Dependency> {
+        let layout = self.layout;
+        self.transition_to(usage, layout)
+    }
+
+    fn transition_to(&mut self, usage: Usage, new_layout: vk::ImageLayout) -> Option<Dependency> {
+        let (stages, access) = (usage.stages(), usage.access());
+        if !access.intersects(write_access()) && new_layout == self.layout {
+            // Reads only have to wait for the last write, and only once.
+            if self.read_stages.subset(stages) && self.read_access.subset(access) {
+                return None;
+            }
+            self.read_stages |= stages;
+            self.read_access |= access;
+            if self.write_stages.is_empty() {
+                return None;
+            }
+            return Some(Dependency {
+                src_stages: self.write_stages,
+                src_access: self.write_access,
+                dst_stages: stages,
+                dst_access: access,
+                old_layout: self.layout,
+                new_layout,
+            });
+        }
+        // Writes and layout transitions have to wait for every earlier access.
+        let dependency = Dependency {
+            src_stages: self.write_stages | self.read_stages,
+            src_access: self.write_access,
+            dst_stages: stages,
+            dst_access: access,
+            old_layout: self.layout,
+            new_layout,
+        };
+        // A layout transition to a read only usage is already visible to that usage.
+        let written = access & write_access();
+        let (read_stages, read_access) = if written.is_empty() {
+            (stages, access)
+        } else {
+            (vk::PipelineStageFlags::empty(), vk::AccessFlags::empty())
+        };
+        *self = State {
+            layout: new_layout,
+            write_stages: stages,
+            write_access: written,
+            read_stages,
+            read_access,
+        };
+        if dependency.src_stages.is_empty() && dependency.old_layout == dependency.new_layout {
+            return None;
+        }
+        Some(dependency)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Dependency {
+    src_stages: vk::PipelineStageFlags,
+    src_access: vk::AccessFlags,
+    dst_stages: vk::PipelineStageFlags,
+    dst_access: vk::AccessFlags,
+    old_layout: vk::ImageLayout,
+    new_layout: vk::ImageLayout,
+}
+
+struct ImageState {
+    mip_levels: u32,
+    array_layers: u32,
+    // Indexed by `mip_level * array_layers + array_layer`.
+    subresources: Vec<State>,
+}
+
+struct BufferState {
+    size: vk::DeviceSize,
+    // Sorted, non overlapping `(offset, end, state)` ranges that cover the whole buffer.
+    ranges: Vec<(vk::DeviceSize, vk::DeviceSize, State)>,
+}
+
+impl BufferState {
+    /// Makes sure that a range starts at `offset`.
+    fn split(&mut self, offset: vk::DeviceSize) {
+        let index = self.ranges.iter().position(|&(start, end, _)| start < offset && offset < end);
+        if let Some(index) = index {
+            let (start, end, state) = self.ranges[index];
+            self.ranges[index] = (start, offset, state);
+            self.ranges.insert(index + 1, (offset, end, state));
+        }
+    }
+
+    fn merge(&mut self) {
+        let mut index = 1;
+        while index < self.ranges.len() {
+            let (_, end, state) = self.ranges[index];
+            if self.ranges[index - 1].2 == state {
+                self.ranges[index - 1].1 = end;
+                self.ranges.remove(index);
+            } else {
+                index += 1;
+            }
+        }
+    }
+}
+
+/// Tracks the layout and the last accesses of images and buffers per subresource and byte
+/// range, and turns the next usage of a resource into the barriers that it needs.
+///
+/// The tracker assumes that command buffers execute in the order their commands were recorded
+/// through it. Transitions are batched until `flush`, which has to be called before recording
+/// the commands that use the resources. A resource must not be transitioned twice between two
+/// flushes. Queue family ownership transfers are not handled.
+#[derive(Default)]
+pub struct ResourceTracker {
+    images: HashMap<vk::Image, ImageState>,
+    buffers: HashMap<vk::Buffer, BufferState>,
+    src_stages: vk::PipelineStageFlags,
+    dst_stages: vk::PipelineStageFlags,
+    image_barriers: Vec<vk::ImageMemoryBarrier>,
+    buffer_barriers: Vec<vk::BufferMemoryBarrier>,
+}
+
+impl ResourceTracker {
+    pub fn new() -> ResourceTracker {
+        ResourceTracker::default()
+    }
+
+    /// Starts tracking `image`, whose subresources are all in `layout`. That is `Undefined`
+    /// for new images and `PresentSrcKhr` for acquired swapchain images.
+    pub fn register_image(&mut self,
+                          image: vk::Image,
+                          mip_levels: u32,
+                          array_layers: u32,
+                          layout: vk::ImageLayout) {
+        self.insert_image(image, mip_levels, array_layers, State::new(layout));
+    }
+
+    /// Like `register_image`, but the first access waits for `wait_stages`. For an image that
+    /// is guarded by a semaphore, such as an acquired swapchain image, these are the stages
+    /// the submission waits on the semaphore in, so that the first layout transition can't
+    /// happen before the wait.
+    pub fn register_image_after(&mut self,
+                                image: vk::Image,
+                                mip_levels: u32,
+                                array_layers: u32,
+                                layout: vk::ImageLayout,
+                                wait_stages: vk::PipelineStageFlags) {
+        let mut state = State::new(layout);
+        state.write_stages = wait_stages;
+        self.insert_image(image, mip_levels, array_layers, state);
+    }
+
+    /// Starts tracking `image`, which is bound to the same memory as the already tracked
+    /// `previous`. Its contents start out undefined, and its first access waits for every
+    /// access to `previous` recorded so far.
+    pub fn alias_image(&mut self,
+                       image: vk::Image,
+                       mip_levels: u32,
+                       array_layers: u32,
+                       previous: vk::Image)
+                       -> Result<(), TrackerError> {
+        let mut state = State::new(vk::ImageLayout::Undefined);
+        for subresource in &self.images
+            .get(&previous)
+            .ok_or(TrackerError::UnknownImage(previous))?
+            .subresources {
+            state.write_stages = state.write_stages | subresource.write_stages |
+                                 subresource.read_stages;
+            state.write_access |= subresource.write_access;
+        }
+        self.insert_image(image, mip_levels, array_layers, state);
+        Ok(())
+    }
+
+    fn insert_image(&mut self, image: vk::Image, mip_levels: u32, array_layers: u32, state: State) {
+        let count = (mip_levels * array_layers) as usize;
+        self.images.insert(image,
+                           ImageState {
+                               mip_levels,
+                               array_layers,
+                               subresources: vec![state; count],
+                           });
+    }
+
+    pub fn register_buffer(&mut self, buffer: vk::Buffer, size: vk::DeviceSize) {
+        self.buffers.insert(buffer,
+                            BufferState {
+                                size,
+                                ranges: vec![(0, size, State::new(vk::ImageLayout::Undefined))],
+                            });
+    }
+
+    pub fn unregister_image(&mut self, image: vk::Image) {
+        self.images.remove(&image);
+    }
+
+    pub fn unregister_buffer(&mut self, buffer: vk::Buffer) {
+        self.buffers.remove(&buffer);
+    }
+
+    /// The layout a subresource will be in once the recorded commands have executed.
+    pub fn image_layout(&self,
+                        image: vk::Image,
+                        mip_level: u32,
+                        array_layer: u32)
+                        -> Option<vk::ImageLayout> {
+        self.images.get(&image).and_then(|state| {
+            if mip_level >= state.mip_levels || array_layer >= state.array_layers {
+                return None;
+            }
+            let index = (mip_level * state.array_layers + array_layer) as usize;
+            Some(state.subresources[index].layout)
+        })
+    }
+
+    /// Prepares `range` of `image` for `usage`. Subresources that need the same barrier share
+    /// a single `ImageMemoryBarrier`.
+    pub fn transition_image(&mut self,
+                            image: vk::Image,
+                            range: &vk::ImageSubresourceRange,
+                            usage: Usage)
+                            -> Result<(), TrackerError> {
+        let state = self.images.get_mut(&image).ok_or(TrackerError::UnknownImage(image))?;
+        // Also resolves `VK_REMAINING_MIP_LEVELS` and `VK_REMAINING_ARRAY_LAYERS`.
+        let level_end = range.base_mip_level +
+                        cmp::min(range.level_count,
+                                 state.mip_levels.saturating_sub(range.base_mip_level));
+        let layer_end = range.base_array_layer +
+                        cmp::min(range.layer_count,
+                                 state.array_layers.saturating_sub(range.base_array_layer));
+        // Runs of `(base_mip_level, level_count, base_array_layer, layer_count, dependency)`.
+        let mut runs: Vec<(u32, u32, u32, u32, Dependency)> = Vec::new();
+        for level in range.base_mip_level..level_end {
+            let mut level_runs: Vec<(u32, u32, u32, u32, Dependency)> = Vec::new();
+            for layer in range.base_array_layer..layer_end {
+                let index = (level * state.array_layers + layer) as usize;
+                let dependency = match state.subresources[index].transition(usage) {
+                    Some(dependency) => dependency,
+                    None => continue,
+                };
+                match level_runs.last_mut() {
+                    Some(run) if run.4 == dependency && run.2 + run.3 == layer => run.3 += 1,
+                    _ => level_runs.push((level, 1, layer, 1, dependency)),
+                }
+            }
+            // Merge with the previous level if it covers the same layers the same way.
+            for run in level_runs {
+                let merged = runs.iter_mut().any(|previous| {
+                    if previous.0 + previous.1 == level && previous.2 == run.2 &&
+                       previous.3 == run.3 && previous.4 == run.4 {
+                        previous.1 += 1;
+                        true
+                    } else {
+                        false
+                    }
+                });
+                if !merged {
+                    runs.push(run);
+                }
+            }
+        }
+        for (base_mip_level, level_count, base_array_layer, layer_count, dependency) in runs {
+            self.src_stages |= dependency.src_stages;
+            self.dst_stages |= dependency.dst_stages;
+            self.image_barriers.push(vk::ImageMemoryBarrier {
+                s_type: vk::StructureType::ImageMemoryBarrier,
+                p_next: ptr::null(),
+                src_access_mask: dependency.src_access,
+                dst_access_mask: dependency.dst_access,
+                old_layout: dependency.old_layout,
+                new_layout: dependency.new_layout,
+                src_queue_family_index: vk::VK_QUEUE_FAMILY_IGNORED,
+                dst_queue_family_index: vk::VK_QUEUE_FAMILY_IGNORED,
+                image,
+                subresource_range: vk::ImageSubresourceRange {
+                    aspect_mask: range.aspect_mask,
+                    base_mip_level,
+                    level_count,
+                    base_array_layer,
+                    layer_count,
+                },
+            });
+        }
+        Ok(())
+    }
+
+    /// Prepares `size` bytes of `buffer` at `offset` for `usage`, `size` can be `VK_WHOLE_SIZE`.
+    pub fn transition_buffer(&mut self,
+                             buffer: vk::Buffer,
+                             offset: vk::DeviceSize,
+                             size: vk::DeviceSize,
+                             usage: Usage)
+                             -> Result<(), TrackerError> {
+        let state = self.buffers.get_mut(&buffer).ok_or(TrackerError::UnknownBuffer(buffer))?;
+        let end = if size == vk::VK_WHOLE_SIZE {
+            state.size
+        } else {
+            cmp::min(offset + size, state.size)
+        };
+        state.split(offset);
+        state.split(end);
+        // Adjacent ranges that need the same barrier share a `BufferMemoryBarrier`.
+        let mut runs: Vec<(vk::DeviceSize, vk::DeviceSize, Dependency)> = Vec::new();
+        for range in state.ranges.iter_mut() {
+            if range.0 < offset || range.1 > end {
+                continue;
+            }
+            let dependency = match range.2.transition_buffer(usage) {
+                Some(dependency) => dependency,
+                None => continue,
+            };
+            match runs.last_mut() {
+                Some(run) if run.2 == dependency && run.1 == range.0 => run.1 = range.1,
+                _ => runs.push((range.0, range.1, dependency)),
+            }
+        }
+        state.merge();
+        for (start, end, dependency) in runs {
+            self.src_stages |= dependency.src_stages;
+            self.dst_stages |= dependency.dst_stages;
+            self.buffer_barriers.push(vk::BufferMemoryBarrier {
+                s_type: vk::StructureType::BufferMemoryBarrier,
+                p_next: ptr::null(),
+                src_access_mask: dependency.src_access,
+                dst_access_mask: dependency.dst_access,
+                src_queue_family_index: vk::VK_QUEUE_FAMILY_IGNORED,
+                dst_queue_family_index: vk::VK_QUEUE_FAMILY_IGNORED,
+                buffer,
+                offset: start,
+                size: end - start,
+            });
+        }
+        Ok(())
+    }
+
+    /// True if there are barriers waiting for `flush`.
+    pub fn has_pending(&self) -> bool {
+        !self.image_barriers.is_empty() || !self.buffer_barriers.is_empty()
+    }
+
+    /// Records every pending barrier with a single `cmd_pipeline_barrier`.
+    pub fn flush(&mut self, device: &Device, command_buffer: vk::CommandBuffer) {
+        if !self.has_pending() {
+            return;
+        }
+        // Layout transitions of resources that were never accessed have nothing to wait for.
+        let src_stages = if self.src_stages.is_empty() {
+            vk::PIPELINE_STAGE_TOP_OF_PIPE_BIT
+        } else {
+            self.src_stages
+        };
+        device.cmd_pipeline_barrier(command_buffer,
+                                    src_stages,
+                                    self.dst_stages,
+                                    vk::DependencyFlags::empty(),
+                                    &[],
+                                    &self.buffer_barriers,
+                                    &self.image_barriers);
+        self.src_stages = vk::PipelineStageFlags::empty();
+        self.dst_stages = vk::PipelineStageFlags::empty();
+        self.image_barriers.clear();
+        self.buffer_barriers.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vk;
+    use super::{State, Usage};
+
+    fn fragment_shader() -> vk::PipelineStageFlags {
+        vk::PIPELINE_STAGE_FRAGMENT_SHADER_BIT
+    }
+
+    #[test]
+    fn first_buffer_write_needs_no_barrier() {
+        let mut state = State::new(vk::ImageLayout::Undefined);
+        assert_eq!(state.transition_buffer(Usage::TransferDst), None);
+    }
+
+    #[test]
+    fn buffer_reads_after_reads_need_no_barrier() {
+        let mut state = State::new(vk::ImageLayout::Undefined);
+        assert_eq!(state.transition_buffer(Usage::StorageRead(fragment_shader())), None);
+        assert_eq!(state.transition_buffer(Usage::TransferSrc), None);
+        assert_eq!(state.transition_buffer(Usage::UniformRead(fragment_shader())), None);
+    }
+
+    #[test]
+    fn buffer_read_waits_for_write_once() {
+        let mut state = State::new(vk::ImageLayout::Undefined);
+        state.transition_buffer(Usage::TransferDst);
+        let dependency = state.transition_buffer(Usage::VertexBuffer).unwrap();
+        assert_eq!(dependency.src_stages, vk::PIPELINE_STAGE_TRANSFER_BIT);
+        assert_eq!(dependency.src_access, vk::ACCESS_TRANSFER_WRITE_BIT);
+        assert_eq!(dependency.dst_stages, vk::PIPELINE_STAGE_VERTEX_INPUT_BIT);
+        assert_eq!(dependency.dst_access, vk::ACCESS_VERTEX_ATTRIBUTE_READ_BIT);
+        assert_eq!(state.transition_buffer(Usage::VertexBuffer), None);
+    }
+
+    #[test]
+    fn buffer_write_waits_for_earlier_reads() {
+        let mut state = State::new(vk::ImageLayout::Undefined);
+        state.transition_buffer(Usage::TransferDst);
+        state.transition_buffer(Usage::UniformRead(fragment_shader()));
+        let dependency = state.transition_buffer(Usage::TransferDst).unwrap();
+        assert_eq!(dependency.src_stages,
+                   vk::PIPELINE_STAGE_TRANSFER_BIT | fragment_shader());
+        assert_eq!(dependency.dst_access, vk::ACCESS_TRANSFER_WRITE_BIT);
+    }
+
+    #[test]
+    fn first_image_use_transitions_layout() {
+        let mut state = State::new(vk::ImageLayout::Undefined);
+        let dependency = state.transition(Usage::TransferDst).unwrap();
+        assert!(dependency.src_stages.is_empty());
+        assert_eq!(dependency.old_layout, vk::ImageLayout::Undefined);
+        assert_eq!(dependency.new_layout, vk::ImageLayout::TransferDstOptimal);
+    }
+
+    #[test]
+    fn image_reads_in_another_layout_transition() {
+        let mut state = State::new(vk::ImageLayout::Undefined);
+        state.transition(Usage::TransferDst);
+        let dependency = state.transition(Usage::Sampled(fragment_shader())).unwrap();
+        assert_eq!(dependency.old_layout, vk::ImageLayout::TransferDstOptimal);
+        assert_eq!(dependency.new_layout, vk::ImageLayout::ShaderReadOnlyOptimal);
+        assert_eq!(state.transition(Usage::Sampled(fragment_shader())), None);
+        let dependency = state.transition(Usage::TransferSrc).unwrap();
+        assert_eq!(dependency.old_layout, vk::ImageLayout::ShaderReadOnlyOptimal);
+        assert_eq!(dependency.new_layout, vk::ImageLayout::TransferSrcOptimal);
+    }
+}
@@ -0,0 +1,287 @@
+use prelude::*;
+use std::marker::PhantomData;
+use std::ptr;
+use vk;
+use device::Device;
+
+/// The command buffer has not been begun, or was reset.
+pub struct Initial;
+/// Commands can be recorded, but no render pass is active.
+pub struct Recording;
+/// A render pass is active, so draws can be recorded.
+pub struct InRenderPass;
+/// Recording has ended and the command buffer can be submitted.
+pub struct Executable;
+
+/// The states in which commands that are valid both inside and outside of a render pass, such
+/// as binding pipelines, can be recorded.
+pub trait ActiveState {}
+
+impl ActiveState for Recording {}
+impl ActiveState for InRenderPass {}
+
+/// A `vk::CommandBuffer` whose recording state is part of its type, so that e.g. drawing
+/// outside of a render pass or submitting a command buffer that was not ended doesn't compile.
+/// Every state transition consumes the recorder and returns it in the new state.
+///
+/// `handle` gives access to the raw command buffer for commands that are not wrapped here,
+/// those are not checked.
+pub struct CommandBufferRecorder<'a, S> {
+    device: &'a Device<'a>,
+    command_buffer: vk::CommandBuffer,
+    state: PhantomData<S>,
+}
+
+impl<'a, S> CommandBufferRecorder<'a, S> {
+    pub fn handle(&self) -> vk::CommandBuffer {
+        self.command_buffer
+    }
+
+    fn into_state<T>(self) -> CommandBufferRecorder<'a, T> {
+        CommandBufferRecorder {
+            device: self.device,
+            command_buffer: self.command_buffer,
+            state: PhantomData,
+        }
+    }
+}
+
+impl<'a> CommandBufferRecorder<'a, Initial> {
+    /// `command_buffer` has to be in the initial state, e.g. freshly allocated.
+    pub fn new(device: &'a Device<'a>,
+               command_buffer: vk::CommandBuffer)
+               -> CommandBufferRecorder<'a, Initial> {
+        CommandBufferRecorder {
+            device,
+            command_buffer,
+            state: PhantomData,
+        }
+    }
+
+    pub fn begin(self,
+                 flags: vk::CommandBufferUsageFlags)
+                 -> VkResult<CommandBufferRecorder<'a, Recording>> {
+        let begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::CommandBufferBeginInfo,
+            p_next: ptr::null(),
+            flags,
+            p_inheritance_info: ptr::null(),
+        };
+        self.device.begin_command_buffer(self.command_buffer, &begin_info)?;
+        Ok(self.into_state())
+    }
+}
+
+impl<'a, S: ActiveState> CommandBufferRecorder<'a, S> {
+    pub fn bind_pipeline(&self,
+                         pipeline_bind_point: vk::PipelineBindPoint,
+                         pipeline: vk::Pipeline)
+                         -> &Self {
+        self.device.cmd_bind_pipeline(self.command_buffer, pipeline_bind_point, pipeline);
+        self
+    }
+
+    pub fn bind_descriptor_sets(&self,
+                                pipeline_bind_point: vk::PipelineBindPoint,
+                                layout: vk::PipelineLayout,
+                                first_set: vk::uint32_t,
+                                descriptor_sets: &[vk::DescriptorSet],
+                                dynamic_offsets: &[vk::uint32_t])
+                                -> &Self {
+        self.device.cmd_bind_descriptor_sets(self.command_buffer,
+                                             pipeline_bind_point,
+                                             layout,
+                                             first_set,
+                                             descriptor_sets,
+                                             dynamic_offsets);
+        self
+    }
+
+    pub fn bind_vertex_buffers(&self, buffers: &[vk::Buffer], offsets: &vk::DeviceSize) -> &Self {
+        self.device.cmd_bind_vertex_buffers(self.command_buffer, buffers, offsets);
+        self
+    }
+
+    pub fn bind_index_buffer(&self,
+                             buffer: vk::Buffer,
+                             offset: vk::DeviceSize,
+                             index_type: vk::IndexType)
+                             -> &Self {
+        self.device.cmd_bind_index_buffer(self.command_buffer, buffer, offset, index_type);
+        self
+    }
+
+    pub fn push_constants<T: Copy>(&self,
+                                   layout: vk::PipelineLayout,
+                                   stage_flags: vk::ShaderStageFlags,
+                                   offset: vk::uint32_t,
+                                   constants: &T)
+                                   -> &Self {
+        self.device.cmd_push_constants(self.command_buffer, layout, stage_flags, offset, constants);
+        self
+    }
+
+    pub fn set_viewport(&self, viewports: &[vk::Viewport]) -> &Self {
+        self.device.cmd_set_viewport(self.command_buffer, viewports);
+        self
+    }
+
+    pub fn set_scissor(&self, scissors: &[vk::Rect2D]) -> &Self {
+        self.device.cmd_set_scissor(self.command_buffer, scissors);
+        self
+    }
+
+    pub fn pipeline_barrier(&self,
+                            src_stage_mask: vk::PipelineStageFlags,
+                            dst_stage_mask: vk::PipelineStageFlags,
+                            dependency_flags: vk::DependencyFlags,
+                            memory_barriers: &[vk::MemoryBarrier],
+                            buffer_memory_barriers: &[vk::BufferMemoryBarrier],
+                            image_memory_barriers: &[vk::ImageMemoryBarrier])
+                            -> &Self {
+        self.device.cmd_pipeline_barrier(self.command_buffer,
+                                         src_stage_mask,
+                                         dst_stage_mask,
+                                         dependency_flags,
+                                         memory_barriers,
+                                         buffer_memory_barriers,
+                                         image_memory_barriers);
+        self
+    }
+}
+
+impl<'a> CommandBufferRecorder<'a, Recording> {
+    pub fn begin_render_pass(self,
+                             begin_info: &vk::RenderPassBeginInfo,
+                             contents: vk::SubpassContents)
+                             -> CommandBufferRecorder<'a, InRenderPass> {
+        self.device.cmd_begin_render_pass(self.command_buffer, begin_info, contents);
+        self.into_state()
+    }
+
+    pub fn copy_buffer(&self,
+                       src_buffer: vk::Buffer,
+                       dst_buffer: vk::Buffer,
+                       regions: &[vk::BufferCopy])
+                       -> &Self {
+        self.device.cmd_copy_buffer(self.command_buffer, src_buffer, dst_buffer, regions);
+        self
+    }
+
+    pub fn copy_buffer_to_image(&self,
+                                src_buffer: vk::Buffer,
+                                dst_image: vk::Image,
+                                dst_image_layout: vk::ImageLayout,
+                                regions: &[vk::BufferImageCopy])
+                                -> &Self {
+        self.device.cmd_copy_buffer_to_image(self.command_buffer,
+                                             src_buffer,
+                                             dst_image,
+                                             dst_image_layout,
+                                             regions);
+        self
+    }
+
+    pub fn end(self) -> VkResult<CommandBufferRecorder<'a, Executable>> {
+        self.device.end_command_buffer(self.command_buffer)?;
+        Ok(self.into_state())
+    }
+}
+
+impl<'a> CommandBufferRecorder<'a, InRenderPass> {
+    pub fn draw(&self,
+                vertex_count: vk::uint32_t,
+                instance_count: vk::uint32_t,
+                first_vertex: vk::uint32_t,
+                first_instance: vk::uint32_t)
+                -> &Self {
+        self.device.cmd_draw(self.command_buffer,
+                             vertex_count,
+                             instance_count,
+                             first_vertex,
+                             first_instance);
+        self
+    }
+
+    pub fn draw_indexed(&self,
+                        index_count: vk::uint32_t,
+                        instance_count: vk::uint32_t,
+                        first_index: vk::uint32_t,
+                        vertex_offset: vk::int32_t,
+                        first_instance: vk::uint32_t)
+                        -> &Self {
+        self.device.cmd_draw_indexed(self.command_buffer,
+                                     index_count,
+                                     instance_count,
+                                     first_index,
+                                     vertex_offset,
+                                     first_instance);
+        self
+    }
+
+    pub fn draw_indirect(&self,
+                         buffer: vk::Buffer,
+                         offset: vk::DeviceSize,
+                         draw_count: vk::uint32_t,
+                         stride: vk::uint32_t)
+                         -> &Self {
+        self.device.cmd_draw_indirect(self.command_buffer, buffer, offset, draw_count, stride);
+        self
+    }
+
+    pub fn draw_indexed_indirect(&self,
+                                 buffer: vk::Buffer,
+                                 offset: vk::DeviceSize,
+                                 draw_count: vk::uint32_t,
+                                 stride: vk::uint32_t)
+                                 -> &Self {
+        self.device
+            .cmd_draw_indexed_indirect(self.command_buffer, buffer, offset, draw_count, stride);
+        self
+    }
+
+    pub fn next_subpass(&self, contents: vk::SubpassContents) -> &Self {
+        self.device.cmd_next_subpass(self.command_buffer, contents);
+        self
+    }
+
+    pub fn end_render_pass(self) -> CommandBufferRecorder<'a, Recording> {
+        self.device.cmd_end_render_pass(self.command_buffer);
+        self.into_state()
+    }
+}
+
+impl<'a> CommandBufferRecorder<'a, Executable> {
+    /// Submits the command buffer to `queue`. Each wait semaphore is paired with the stages
+    /// that wait on it.
+    pub fn submit(&self,
+                  queue: vk::Queue,
+                  wait_semaphores: &[(vk::Semaphore, vk::PipelineStageFlags)],
+                  signal_semaphores: &[vk::Semaphore],
+                  fence: vk::Fence)
+                  -> VkResult<()> {
+        let (semaphores, stages): (Vec<_>, Vec<_>) = wait_semaphores.iter().cloned().unzip();
+        let submit_info = vk::SubmitInfo {
+            s_type: vk::StructureType::SubmitInfo,
+            p_next: ptr::null(),
+            wait_semaphore_count: semaphores.len() as vk::uint32_t,
+            p_wait_semaphores: semaphores.as_ptr(),
+            p_wait_dst_stage_mask: stages.as_ptr(),
+            command_buffer_count: 1,
+            p_command_buffers: &self.command_buffer,
+            signal_semaphore_count: signal_semaphores.len() as vk::uint32_t,
+            p_signal_semaphores: signal_semaphores.as_ptr(),
+        };
+        self.device.queue_submit(queue, &[submit_info], fence)
+    }
+
+    /// Returns the command buffer to the initial state. The pool it was allocated from has to
+    /// have been created with `COMMAND_POOL_CREATE_RESET_COMMAND_BUFFER_BIT`, and the device
+    /// must be done with it.
+    pub fn reset(self,
+                 flags: vk::CommandBufferResetFlags)
+                 -> VkResult<CommandBufferRecorder<'a, Initial>> {
+        self.device.reset_command_buffer(self.command_buffer, flags)?;
+        Ok(self.into_state())
+    }
+}
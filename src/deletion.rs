@@ -0,0 +1,161 @@
+use prelude::*;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use vk;
+use device::Device;
+
+/// A handle that `DeletionQueue` can destroy with the matching `Device::destroy_*` function.
+#[derive(Debug, Clone, Copy)]
+pub enum Destroyable {
+    Buffer(vk::Buffer),
+    Image(vk::Image),
+    ImageView(vk::ImageView),
+    Framebuffer(vk::Framebuffer),
+    RenderPass(vk::RenderPass),
+    Pipeline(vk::Pipeline),
+    PipelineLayout(vk::PipelineLayout),
+    ShaderModule(vk::ShaderModule),
+    Semaphore(vk::Semaphore),
+    Fence(vk::Fence),
+    CommandPool(vk::CommandPool),
+    DescriptorPool(vk::DescriptorPool),
+    DescriptorSetLayout(vk::DescriptorSetLayout),
+    SwapchainKHR(vk::SwapchainKHR),
+    /// Freed with `free_memory`.
+    DeviceMemory(vk::DeviceMemory),
+}
+
+impl Destroyable {
+    fn destroy(self, device: &Device) {
+        match self {
+            Destroyable::Buffer(buffer) => device.destroy_buffer(buffer),
+            Destroyable::Image(image) => device.destroy_image(image),
+            Destroyable::ImageView(view) => device.destroy_image_view(view),
+            Destroyable::Framebuffer(framebuffer) => device.destroy_framebuffer(framebuffer),
+            Destroyable::RenderPass(render_pass) => device.destroy_render_pass(render_pass),
+            Destroyable::Pipeline(pipeline) => device.destroy_pipeline(pipeline),
+            Destroyable::PipelineLayout(layout) => device.destroy_pipeline_layout(layout),
+            Destroyable::ShaderModule(module) => device.destroy_shader_module(module),
+            Destroyable::Semaphore(semaphore) => device.destroy_semaphore(semaphore),
+            Destroyable::Fence(fence) => device.destroy_fence(fence),
+            Destroyable::CommandPool(pool) => device.destroy_command_pool(pool),
+            Destroyable::DescriptorPool(pool) => device.destroy_descriptor_pool(pool),
+            Destroyable::DescriptorSetLayout(layout) => {
+                device.destroy_descriptor_set_layout(layout)
+            }
+            Destroyable::SwapchainKHR(swapchain) => device.destroy_swapchain_khr(swapchain),
+            Destroyable::DeviceMemory(memory) => device.free_memory(memory),
+        }
+    }
+}
+
+macro_rules! destroyable_from {
+    ($($variant: ident),*) => {
+        $(
+            impl From<vk::$variant> for Destroyable {
+                fn from(handle: vk::$variant) -> Destroyable {
+                    Destroyable::$variant(handle)
+                }
+            }
+        )*
+    }
+}
+
+destroyable_from!(Buffer,
+                  Image,
+                  ImageView,
+                  Framebuffer,
+                  RenderPass,
+                  Pipeline,
+                  PipelineLayout,
+                  ShaderModule,
+                  Semaphore,
+                  Fence,
+                  CommandPool,
+                  DescriptorPool,
+                  DescriptorSetLayout,
+                  SwapchainKHR,
+                  DeviceMemory);
+
+/// Defers destroying handles until the device is done with them, instead of waiting for the
+/// device to be idle.
+///
+/// Every handle is queued with the fence of the last submission that uses it, e.g.
+/// `Frame::fence` for a resource used in the current frame. `collect` destroys the handles
+/// whose fence has signaled, and dropping the queue waits for the device to be idle and
+/// destroys everything that is left. A fence that is reset before `collect` saw it signaled
+/// delays the destruction until it signals again.
+///
+/// Queued fences have to outlive the queue, e.g. the `FrameSync` that owns `Frame::fence` has
+/// to be dropped after it.
+pub struct DeletionQueue<'a> {
+    device: &'a Device<'a>,
+    pending: Vec<(vk::Fence, Destroyable)>,
+}
+
+impl<'a> DeletionQueue<'a> {
+    pub fn new(device: &'a Device<'a>) -> DeletionQueue<'a> {
+        DeletionQueue {
+            device,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Destroys `handle` once `fence` has signaled. A null fence means the handle is not in use
+    /// and is destroyed by the next `collect`.
+    pub fn destroy_after<T: Into<Destroyable>>(&mut self, fence: vk::Fence, handle: T) {
+        self.pending.push((fence, handle.into()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Destroys every handle whose fence has signaled and returns how many were destroyed.
+    /// Each fence is only queried once.
+    pub fn collect(&mut self) -> VkResult<usize> {
+        let mut signaled: HashMap<vk::Fence, bool> = HashMap::new();
+        signaled.insert(vk::Fence::null(), true);
+        for &(fence, _) in &self.pending {
+            if let Entry::Vacant(entry) = signaled.entry(fence) {
+                let status = self.device.get_fence_status(fence)?;
+                entry.insert(status);
+            }
+        }
+        let device = self.device;
+        let count = self.pending.len();
+        self.pending.retain(|&(fence, handle)| {
+            if signaled[&fence] {
+                handle.destroy(device);
+                false
+            } else {
+                true
+            }
+        });
+        Ok(count - self.pending.len())
+    }
+
+    /// Waits for the device to be idle and destroys all queued handles. Queued fences are not
+    /// waited on, they may have been reset without being submitted again.
+    pub fn flush(&mut self) -> VkResult<()> {
+        self.device.device_wait_idle()?;
+        for (_, handle) in self.pending.drain(..) {
+            handle.destroy(self.device);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for DeletionQueue<'a> {
+    /// Handles are destroyed even if waiting fails, e.g. after the device was lost.
+    fn drop(&mut self) {
+        let _ = self.device.device_wait_idle();
+        for (_, handle) in self.pending.drain(..) {
+            handle.destroy(self.device);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use prelude::*;
+use std::ptr;
+use vk;
+use device::Device;
+
+/// A descriptor set together with the pool it was allocated from, which is needed to free it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PooledDescriptorSet {
+    pub set: vk::DescriptorSet,
+    pub pool: vk::DescriptorPool,
+}
+
+/// Allocates descriptor sets from a list of pools, creating another pool whenever the current
+/// one is exhausted.
+///
+/// Every pool has room for `sets_per_pool` sets, and `descriptor_count` of each of the
+/// `pool_sizes` is the number of descriptors of that type per set, so the pool sizes are
+/// ratios rather than totals. `reset` returns every set at once, e.g. keep one allocator per
+/// frame in flight and reset it once the frame's fence has signaled.
+pub struct DescriptorAllocator<'a> {
+    device: &'a Device<'a>,
+    pool_sizes: Vec<vk::DescriptorPoolSize>,
+    sets_per_pool: vk::uint32_t,
+    flags: vk::DescriptorPoolCreateFlags,
+    // The last pool is the one that is allocated from.
+    used_pools: Vec<vk::DescriptorPool>,
+    // Pools that were reset and can be reused.
+    free_pools: Vec<vk::DescriptorPool>,
+}
+
+impl<'a> DescriptorAllocator<'a> {
+    /// Pass `DESCRIPTOR_POOL_CREATE_FREE_DESCRIPTOR_SET_BIT` in `flags` to be able to `free`
+    /// individual sets.
+    pub fn new(device: &'a Device<'a>,
+               pool_sizes: &[vk::DescriptorPoolSize],
+               sets_per_pool: vk::uint32_t,
+               flags: vk::DescriptorPoolCreateFlags)
+               -> DescriptorAllocator<'a> {
+        DescriptorAllocator {
+            device,
+            pool_sizes: pool_sizes.to_vec(),
+            sets_per_pool,
+            flags,
+            used_pools: Vec::new(),
+            free_pools: Vec::new(),
+        }
+    }
+
+    /// The number of pools that have been created.
+    pub fn pool_count(&self) -> usize {
+        self.used_pools.len() + self.free_pools.len()
+    }
+
+    /// Allocates one set for each of `layouts`. If the current pool is exhausted, the sets are
+    /// allocated from another pool, so all returned sets share a pool.
+    pub fn allocate(&mut self,
+                    layouts: &[vk::DescriptorSetLayout])
+                    -> VkResult<Vec<PooledDescriptorSet>> {
+        if let Some(&pool) = self.used_pools.last() {
+            match self.allocate_from(pool, layouts) {
+                Err(vk::Result::ErrorFragmentedPool) |
+                Err(vk::Result::ErrorOutOfPoolMemoryKhr) => {}
+                result => return result,
+            }
+        }
+        let pool = self.next_pool()?;
+        self.used_pools.push(pool);
+        self.allocate_from(pool, layouts)
+    }
+
+    /// Returns `sets` to their pools, which requires the
+    /// `DESCRIPTOR_POOL_CREATE_FREE_DESCRIPTOR_SET_BIT` flag.
+    pub fn free(&self, sets: &[PooledDescriptorSet]) -> VkResult<()> {
+        for &pool in &self.used_pools {
+            let pool_sets: Vec<vk::DescriptorSet> = sets.iter()
+                .filter(|set| set.pool == pool)
+                .map(|set| set.set)
+                .collect();
+            if !pool_sets.is_empty() {
+                self.device.free_descriptor_sets(pool, &pool_sets)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Frees every set allocated so far by resetting the pools. None of the sets may still be
+    /// in use by the device.
+    pub fn reset(&mut self) -> VkResult<()> {
+        while let Some(pool) = self.used_pools.pop() {
+            if let Err(err) = self.device
+                .reset_descriptor_pool(pool, vk::DescriptorPoolResetFlags::empty()) {
+                self.used_pools.push(pool);
+                return Err(err);
+            }
+            self.free_pools.push(pool);
+        }
+        Ok(())
+    }
+
+    fn allocate_from(&self,
+                     pool: vk::DescriptorPool,
+                     layouts: &[vk::DescriptorSetLayout])
+                     -> VkResult<Vec<PooledDescriptorSet>> {
+        let allocate_info = vk::DescriptorSetAllocateInfo {
+            s_type: vk::StructureType::DescriptorSetAllocateInfo,
+            p_next: ptr::null(),
+            descriptor_pool: pool,
+            descriptor_set_count: layouts.len() as vk::uint32_t,
+            p_set_layouts: layouts.as_ptr(),
+        };
+        let sets = self.device.allocate_descriptor_sets(&allocate_info)?;
+        Ok(sets.into_iter()
+            .map(|set| {
+                PooledDescriptorSet {
+                    set,
+                    pool,
+                }
+            })
+            .collect())
+    }
+
+    fn next_pool(&mut self) -> VkResult<vk::DescriptorPool> {
+        if let Some(pool) = self.free_pools.pop() {
+            return Ok(pool);
+        }
+        let pool_sizes: Vec<vk::DescriptorPoolSize> = self.pool_sizes
+            .iter()
+            .map(|size| {
+                vk::DescriptorPoolSize {
+                    typ: size.typ,
+                    descriptor_count: size.descriptor_count * self.sets_per_pool,
+                }
+            })
+            .collect();
+        let create_info = vk::DescriptorPoolCreateInfo {
+            s_type: vk::StructureType::DescriptorPoolCreateInfo,
+            p_next: ptr::null(),
+            flags: self.flags,
+            max_sets: self.sets_per_pool,
+            pool_size_count: pool_sizes.len() as vk::uint32_t,
+            p_pool_sizes: pool_sizes.as_ptr(),
+        };
+        self.device.create_descriptor_pool(&create_info)
+    }
+}
+
+impl<'a> Drop for DescriptorAllocator<'a> {
+    /// Destroying the pools frees all sets, the device must be done with them.
+    fn drop(&mut self) {
+        for pool in self.used_pools.drain(..).chain(self.free_pools.drain(..)) {
+            self.device.destroy_descriptor_pool(pool);
+        }
+    }
+}
@@ -1,105 +1,311 @@
 #![allow(dead_code)]
+// Dispatchable handles such as `vk::CommandBuffer` are raw pointers that are only passed on to
+// the driver, never dereferenced.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
 use prelude::*;
 use std::ptr;
 use std::mem;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use vk;
+use allocator::{self, AllocationCallbacks};
+use memory::{HeapUsage, MapError, MappedMemory, MemoryStatistics, MemoryTracker};
+use util;
 
+#[derive(Debug)]
+pub enum PushConstantError {
+    /// Offset and size must both be a multiple of 4.
+    Unaligned { offset: vk::uint32_t, size: vk::uint32_t },
+    /// `offset + size` exceeds `PhysicalDeviceLimits::max_push_constants_size`.
+    ExceedsLimit { end: vk::uint32_t, max: vk::uint32_t },
+    /// A byte at `offset` is not covered by a `PushConstantRange` for every stage in
+    /// `stage_flags`.
+    NotInLayout { offset: vk::uint32_t, stage_flags: vk::ShaderStageFlags },
+    /// A `PushConstantRange` that overlaps the update declares stages that are missing
+    /// from `stage_flags`.
+    MissingStages { offset: vk::uint32_t, range_stage_flags: vk::ShaderStageFlags },
+}
+
+/// Checks a push constant update against the device limits and the push constant ranges
+/// the pipeline layout was created with. These are the rules `vkCmdPushConstants` would
+/// otherwise only have checked by the validation layers.
+pub fn validate_push_constants(limits: &vk::PhysicalDeviceLimits,
+                               ranges: &[vk::PushConstantRange],
+                               stage_flags: vk::ShaderStageFlags,
+                               offset: vk::uint32_t,
+                               size: vk::uint32_t)
+                               -> Result<(), PushConstantError> {
+    if !offset.is_multiple_of(4) || !size.is_multiple_of(4) || size == 0 {
+        return Err(PushConstantError::Unaligned {
+            offset,
+            size,
+        });
+    }
+    let end = offset as u64 + size as u64;
+    if end > limits.max_push_constants_size as u64 {
+        return Err(PushConstantError::ExceedsLimit {
+            end: end as vk::uint32_t,
+            max: limits.max_push_constants_size,
+        });
+    }
+    let mut byte = offset;
+    while (byte as u64) < end {
+        let overlapping = ranges.iter().filter(|range| {
+            range.offset <= byte && (byte as u64) < range.offset as u64 + range.size as u64
+        });
+        let mut covered = vk::ShaderStageFlags::empty();
+        for range in overlapping {
+            if !stage_flags.subset(range.stage_flags) {
+                return Err(PushConstantError::MissingStages {
+                    offset: byte,
+                    range_stage_flags: range.stage_flags,
+                });
+            }
+            covered |= range.stage_flags;
+        }
+        if !covered.subset(stage_flags) {
+            return Err(PushConstantError::NotInLayout {
+                offset: byte,
+                stage_flags,
+            });
+        }
+        byte += 4;
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ShaderModuleError {
+    /// The code is shorter than the five word SPIR-V header.
+    TooShort(usize),
+    /// The first word is not `util::SPIRV_MAGIC`. A byte-swapped magic number means the
+    /// module has the wrong endianness, `util::read_spv` fixes that.
+    BadMagic(vk::uint32_t),
+    VkError(vk::Result),
+}
+
+type MemoryWarningHook = Arc<dyn Fn(&HeapUsage) + Send + Sync>;
 
 pub struct Device<'r> {
     handle: vk::Device,
     device_fn: vk::DeviceFn,
+    limits: vk::PhysicalDeviceLimits,
+    allocation_callbacks: Option<Arc<AllocationCallbacks>>,
+    // Set by `enable_memory_tracking`, allocations are only recorded while this is set.
+    tracking_enabled: AtomicBool,
+    // Size and memory type index of every allocation made while tracking was enabled.
+    allocations: Mutex<HashMap<vk::DeviceMemory, (vk::DeviceSize, vk::uint32_t)>>,
+    memory_tracker: Mutex<Option<MemoryTracker>>,
+    // The warning fraction and hook set by `set_memory_warning`.
+    memory_warning: Mutex<Option<(f64, MemoryWarningHook)>>,
     _lifetime: ::std::marker::PhantomData<&'r ()>,
 }
 
+// Vulkan only requires external synchronization for the objects passed to a command,
+// the device itself can be shared so that command buffers can be recorded on many threads.
+unsafe impl<'r> Send for Device<'r> {}
+unsafe impl<'r> Sync for Device<'r> {}
+
 impl<'r> Device<'r> {
-    pub unsafe fn from_raw(handle: vk::Device, device_fn: vk::DeviceFn) -> Self {
+    pub unsafe fn from_raw(handle: vk::Device,
+                           device_fn: vk::DeviceFn,
+                           limits: vk::PhysicalDeviceLimits,
+                           allocation_callbacks: Option<Arc<AllocationCallbacks>>)
+                           -> Self {
         Device {
-            handle: handle,
-            device_fn: device_fn,
+            handle,
+            device_fn,
+            limits,
+            allocation_callbacks,
+            tracking_enabled: AtomicBool::new(false),
+            allocations: Mutex::new(HashMap::new()),
+            memory_tracker: Mutex::new(None),
+            memory_warning: Mutex::new(None),
             _lifetime: ::std::marker::PhantomData,
         }
     }
+
+    /// The `p_allocator` passed to every device level create and destroy function.
+    pub fn allocation_callbacks(&self) -> *const vk::AllocationCallbacks {
+        allocator::as_ptr(&self.allocation_callbacks)
+    }
+
+    /// The limits of the physical device this device was created from.
+    pub fn limits(&self) -> &vk::PhysicalDeviceLimits {
+        &self.limits
+    }
+
+    /// Returns the `allocation_size` that `memory` was allocated with, or `None` if it was
+    /// not allocated through this device while memory tracking was enabled.
+    pub fn allocation_size(&self, memory: vk::DeviceMemory) -> Option<vk::DeviceSize> {
+        if !self.tracking_enabled.load(Ordering::Acquire) {
+            return None;
+        }
+        self.allocations.lock().unwrap().get(&memory).map(|&(size, _)| size)
+    }
+
+    /// Starts counting the memory allocated per memory type and heap. Allocations are not
+    /// recorded before this is called, so it should be called right after the device is
+    /// created; memory allocated earlier is neither counted nor known to `allocation_size`.
+    ///
+    /// Calling this again resets the warning state and calls the warning hook for every heap
+    /// that is already above the warning fraction.
+    pub fn enable_memory_tracking(&self, memory_properties: vk::PhysicalDeviceMemoryProperties) {
+        let (crossed, hook) = {
+            // The locks are taken in the order allocations, memory_warning, memory_tracker,
+            // and `allocations` is held until the tracker is installed so that no allocation
+            // is missed or counted twice.
+            let allocations = self.allocations.lock().unwrap();
+            let warning = self.memory_warning.lock().unwrap();
+            let mut tracker = MemoryTracker::new(memory_properties);
+            if let Some((fraction, _)) = *warning {
+                tracker.set_warning_fraction(fraction);
+            }
+            let crossed: Vec<HeapUsage> = allocations.values()
+                .filter_map(|&(size, memory_type_index)| {
+                    tracker.record_allocation(memory_type_index, size)
+                })
+                .collect();
+            *self.memory_tracker.lock().unwrap() = Some(tracker);
+            self.tracking_enabled.store(true, Ordering::Release);
+            (crossed, warning.as_ref().map(|(_, hook)| hook.clone()))
+        };
+        if let Some(hook) = hook {
+            for heap_usage in &crossed {
+                hook(heap_usage);
+            }
+        }
+    }
+
+    /// Calls `hook` from `allocate_memory` when the usage of a heap rises above `fraction` of
+    /// its size. Only has an effect while memory tracking is enabled, but can be set before
+    /// `enable_memory_tracking`.
+    pub fn set_memory_warning<F>(&self, fraction: f64, hook: F)
+        where F: Fn(&HeapUsage) + Send + Sync + 'static
+    {
+        let mut warning = self.memory_warning.lock().unwrap();
+        if let Some(ref mut tracker) = *self.memory_tracker.lock().unwrap() {
+            tracker.set_warning_fraction(fraction);
+        }
+        *warning = Some((fraction, Arc::new(hook)));
+    }
+
+    /// Returns `None` if memory tracking is not enabled.
+    pub fn memory_statistics(&self) -> Option<MemoryStatistics> {
+        self.memory_tracker.lock().unwrap().as_ref().map(|tracker| tracker.statistics())
+    }
+
     pub fn destroy_device(&self) {
         unsafe {
-            self.device_fn.destroy_device(self.handle, ptr::null());
+            self.device_fn.destroy_device(self.handle, self.allocation_callbacks());
         }
     }
 
     pub fn free_memory(&self, memory: vk::DeviceMemory) {
+        if self.tracking_enabled.load(Ordering::Acquire) {
+            let mut allocations = self.allocations.lock().unwrap();
+            if let Some((size, memory_type_index)) = allocations.remove(&memory) {
+                if let Some(ref mut tracker) = *self.memory_tracker.lock().unwrap() {
+                    tracker.record_free(memory_type_index, size);
+                }
+            }
+        }
         unsafe {
-            self.device_fn.free_memory(self.handle, memory, ptr::null());
+            self.device_fn.free_memory(self.handle, memory, self.allocation_callbacks());
         }
     }
 
     pub fn destroy_fence(&self, fence: vk::Fence) {
         unsafe {
-            self.device_fn.destroy_fence(self.handle, fence, ptr::null());
+            self.device_fn.destroy_fence(self.handle, fence, self.allocation_callbacks());
         }
     }
 
     pub fn destroy_image(&self, image: vk::Image) {
         unsafe {
-            self.device_fn.destroy_image(self.handle, image, ptr::null());
+            self.device_fn.destroy_image(self.handle, image, self.allocation_callbacks());
         }
     }
 
     pub fn destroy_command_pool(&self, pool: vk::CommandPool) {
         unsafe {
-            self.device_fn.destroy_command_pool(self.handle, pool, ptr::null());
+            self.device_fn.destroy_command_pool(self.handle, pool, self.allocation_callbacks());
         }
     }
 
     pub fn destroy_swapchain_khr(&self, swapchain: vk::SwapchainKHR) {
         unsafe {
-            self.device_fn.destroy_swapchain_khr(self.handle, swapchain, ptr::null());
+            self.device_fn.destroy_swapchain_khr(self.handle,
+                                                 swapchain,
+                                                 self.allocation_callbacks());
         }
     }
 
     pub fn destroy_image_view(&self, image_view: vk::ImageView) {
         unsafe {
-            self.device_fn.destroy_image_view(self.handle, image_view, ptr::null());
+            self.device_fn.destroy_image_view(self.handle, image_view, self.allocation_callbacks());
         }
     }
 
     pub fn destroy_render_pass(&self, renderpass: vk::RenderPass) {
         unsafe {
-            self.device_fn.destroy_render_pass(self.handle, renderpass, ptr::null());
+            self.device_fn.destroy_render_pass(self.handle,
+                                               renderpass,
+                                               self.allocation_callbacks());
         }
     }
 
     pub fn destroy_framebuffer(&self, framebuffer: vk::Framebuffer) {
         unsafe {
-            self.device_fn.destroy_framebuffer(self.handle, framebuffer, ptr::null());
+            self.device_fn.destroy_framebuffer(self.handle,
+                                               framebuffer,
+                                               self.allocation_callbacks());
         }
     }
 
     pub fn destroy_pipeline_layout(&self, pipeline_layout: vk::PipelineLayout) {
         unsafe {
-            self.device_fn.destroy_pipeline_layout(self.handle, pipeline_layout, ptr::null());
+            self.device_fn.destroy_pipeline_layout(self.handle,
+                                                   pipeline_layout,
+                                                   self.allocation_callbacks());
         }
     }
 
     pub fn destroy_buffer(&self, buffer: vk::Buffer) {
         unsafe {
-            self.device_fn.destroy_buffer(self.handle, buffer, ptr::null());
+            self.device_fn.destroy_buffer(self.handle, buffer, self.allocation_callbacks());
         }
     }
 
     pub fn destroy_shader_module(&self, shader: vk::ShaderModule) {
         unsafe {
-            self.device_fn.destroy_shader_module(self.handle, shader, ptr::null());
+            self.device_fn.destroy_shader_module(self.handle, shader, self.allocation_callbacks());
         }
     }
 
     pub fn destroy_pipeline(&self, pipeline: vk::Pipeline) {
         unsafe {
-            self.device_fn.destroy_pipeline(self.handle, pipeline, ptr::null());
+            self.device_fn.destroy_pipeline(self.handle, pipeline, self.allocation_callbacks());
         }
     }
 
     pub fn destroy_semaphore(&self, semaphore: vk::Semaphore) {
         unsafe {
-            self.device_fn.destroy_semaphore(self.handle, semaphore, ptr::null());
+            self.device_fn.destroy_semaphore(self.handle, semaphore, self.allocation_callbacks());
+        }
+    }
+
+    pub fn destroy_descriptor_pool(&self, pool: vk::DescriptorPool) {
+        unsafe {
+            self.device_fn.destroy_descriptor_pool(self.handle, pool, self.allocation_callbacks());
+        }
+    }
+
+    pub fn destroy_descriptor_set_layout(&self, layout: vk::DescriptorSetLayout) {
+        unsafe {
+            self.device_fn
+                .destroy_descriptor_set_layout(self.handle, layout, self.allocation_callbacks());
         }
     }
 
@@ -194,6 +400,44 @@ impl<'r> Device<'r> {
         }
     }
 
+    pub fn cmd_next_subpass(&self,
+                            command_buffer: vk::CommandBuffer,
+                            contents: vk::SubpassContents) {
+        unsafe {
+            self.device_fn.cmd_next_subpass(command_buffer, contents);
+        }
+    }
+
+    pub fn cmd_execute_commands(&self,
+                                primary_command_buffer: vk::CommandBuffer,
+                                secondary_command_buffers: &[vk::CommandBuffer]) {
+        unsafe {
+            self.device_fn.cmd_execute_commands(primary_command_buffer,
+                                                secondary_command_buffers.len() as vk::uint32_t,
+                                                secondary_command_buffers.as_ptr());
+        }
+    }
+
+    /// `T` has to be a non-zero multiple of 4 bytes, `validate_push_constants` checks the
+    /// update against the pipeline layout.
+    pub fn cmd_push_constants<T: Copy>(&self,
+                                       command_buffer: vk::CommandBuffer,
+                                       layout: vk::PipelineLayout,
+                                       stage_flags: vk::ShaderStageFlags,
+                                       offset: vk::uint32_t,
+                                       constants: &T) {
+        debug_assert!(mem::size_of::<T>() != 0 && mem::size_of::<T>().is_multiple_of(4),
+                      "push constants must be a non-zero multiple of 4 bytes");
+        unsafe {
+            self.device_fn.cmd_push_constants(command_buffer,
+                                              layout,
+                                              stage_flags,
+                                              offset,
+                                              mem::size_of::<T>() as vk::uint32_t,
+                                              constants as *const T as *const vk::c_void);
+        }
+    }
+
     pub fn cmd_bind_pipeline(&self,
                              command_buffer: vk::CommandBuffer,
                              pipeline_bind_point: vk::PipelineBindPoint,
@@ -247,6 +491,36 @@ impl<'r> Device<'r> {
         }
     }
 
+    pub fn cmd_copy_buffer(&self,
+                           command_buffer: vk::CommandBuffer,
+                           src_buffer: vk::Buffer,
+                           dst_buffer: vk::Buffer,
+                           regions: &[vk::BufferCopy]) {
+        unsafe {
+            self.device_fn.cmd_copy_buffer(command_buffer,
+                                           src_buffer,
+                                           dst_buffer,
+                                           regions.len() as vk::uint32_t,
+                                           regions.as_ptr());
+        }
+    }
+
+    pub fn cmd_copy_buffer_to_image(&self,
+                                    command_buffer: vk::CommandBuffer,
+                                    src_buffer: vk::Buffer,
+                                    dst_image: vk::Image,
+                                    dst_image_layout: vk::ImageLayout,
+                                    regions: &[vk::BufferImageCopy]) {
+        unsafe {
+            self.device_fn.cmd_copy_buffer_to_image(command_buffer,
+                                                    src_buffer,
+                                                    dst_image,
+                                                    dst_image_layout,
+                                                    regions.len() as vk::uint32_t,
+                                                    regions.as_ptr());
+        }
+    }
+
     pub fn cmd_set_viewport(&self, command_buffer: vk::CommandBuffer, viewports: &[vk::Viewport]) {
         unsafe {
             self.device_fn.cmd_set_viewport(command_buffer,
@@ -256,12 +530,100 @@ impl<'r> Device<'r> {
         }
     }
 
+    pub fn cmd_set_line_width(&self, command_buffer: vk::CommandBuffer, line_width: f32) {
+        unsafe {
+            self.device_fn.cmd_set_line_width(command_buffer, line_width);
+        }
+    }
+
+    pub fn cmd_set_depth_bias(&self,
+                              command_buffer: vk::CommandBuffer,
+                              constant_factor: f32,
+                              clamp: f32,
+                              slope_factor: f32) {
+        unsafe {
+            self.device_fn.cmd_set_depth_bias(command_buffer, constant_factor, clamp, slope_factor);
+        }
+    }
+
+    pub fn cmd_set_blend_constants(&self,
+                                   command_buffer: vk::CommandBuffer,
+                                   blend_constants: &[f32; 4]) {
+        unsafe {
+            self.device_fn.cmd_set_blend_constants(command_buffer, blend_constants);
+        }
+    }
+
+    pub fn cmd_set_depth_bounds(&self,
+                                command_buffer: vk::CommandBuffer,
+                                min_depth_bounds: f32,
+                                max_depth_bounds: f32) {
+        unsafe {
+            self.device_fn.cmd_set_depth_bounds(command_buffer, min_depth_bounds, max_depth_bounds);
+        }
+    }
+
+    pub fn cmd_set_stencil_compare_mask(&self,
+                                        command_buffer: vk::CommandBuffer,
+                                        face_mask: vk::StencilFaceFlags,
+                                        compare_mask: vk::uint32_t) {
+        unsafe {
+            self.device_fn.cmd_set_stencil_compare_mask(command_buffer, face_mask, compare_mask);
+        }
+    }
+
+    pub fn cmd_set_stencil_write_mask(&self,
+                                      command_buffer: vk::CommandBuffer,
+                                      face_mask: vk::StencilFaceFlags,
+                                      write_mask: vk::uint32_t) {
+        unsafe {
+            self.device_fn.cmd_set_stencil_write_mask(command_buffer, face_mask, write_mask);
+        }
+    }
+
+    pub fn cmd_set_stencil_reference(&self,
+                                     command_buffer: vk::CommandBuffer,
+                                     face_mask: vk::StencilFaceFlags,
+                                     reference: vk::uint32_t) {
+        unsafe {
+            self.device_fn.cmd_set_stencil_reference(command_buffer, face_mask, reference);
+        }
+    }
+
+    pub fn cmd_draw_indirect(&self,
+                             command_buffer: vk::CommandBuffer,
+                             buffer: vk::Buffer,
+                             offset: vk::DeviceSize,
+                             draw_count: vk::uint32_t,
+                             stride: vk::uint32_t) {
+        unsafe {
+            self.device_fn.cmd_draw_indirect(command_buffer, buffer, offset, draw_count, stride);
+        }
+    }
+
+    pub fn cmd_draw_indexed_indirect(&self,
+                                     command_buffer: vk::CommandBuffer,
+                                     buffer: vk::Buffer,
+                                     offset: vk::DeviceSize,
+                                     draw_count: vk::uint32_t,
+                                     stride: vk::uint32_t) {
+        unsafe {
+            self.device_fn.cmd_draw_indexed_indirect(command_buffer,
+                                                     buffer,
+                                                     offset,
+                                                     draw_count,
+                                                     stride);
+        }
+    }
+
+    /// Returns the index of the acquired image and whether the swapchain is suboptimal. The
+    /// image is acquired in both cases.
     pub fn acquire_next_image_khr(&self,
                                   swapchain: vk::SwapchainKHR,
                                   timeout: vk::uint64_t,
                                   semaphore: vk::Semaphore,
                                   fence: vk::Fence)
-                                  -> VkResult<vk::uint32_t> {
+                                  -> VkResult<(vk::uint32_t, bool)> {
         unsafe {
             let mut index = mem::uninitialized();
             let err_code = self.device_fn
@@ -272,7 +634,8 @@ impl<'r> Device<'r> {
                                         fence,
                                         &mut index);
             match err_code {
-                vk::Result::Success => Ok(index),
+                vk::Result::Success => Ok((index, false)),
+                vk::Result::SuboptimalKhr => Ok((index, true)),
                 _ => Err(err_code),
             }
         }
@@ -284,7 +647,10 @@ impl<'r> Device<'r> {
         unsafe {
             let mut semaphore = mem::uninitialized();
             let err_code = self.device_fn
-                .create_semaphore(self.handle, create_info, ptr::null(), &mut semaphore);
+                .create_semaphore(self.handle,
+                                  create_info,
+                                  self.allocation_callbacks(),
+                                  &mut semaphore);
             match err_code {
                 vk::Result::Success => Ok(semaphore),
                 _ => Err(err_code),
@@ -303,7 +669,7 @@ impl<'r> Device<'r> {
                                            pipeline_cache,
                                            create_infos.len() as vk::uint32_t,
                                            create_infos.as_ptr(),
-                                           ptr::null(),
+                                           self.allocation_callbacks(),
                                            pipelines.as_mut_ptr());
             pipelines.set_len(create_infos.len());
             match err_code {
@@ -313,11 +679,32 @@ impl<'r> Device<'r> {
         }
     }
 
+    pub fn create_compute_pipelines(&self,
+                                    pipeline_cache: vk::PipelineCache,
+                                    create_infos: &[vk::ComputePipelineCreateInfo])
+                                    -> VkResult<Vec<vk::Pipeline>> {
+        unsafe {
+            let mut pipelines = Vec::with_capacity(create_infos.len());
+            let err_code = self.device_fn
+                .create_compute_pipelines(self.handle,
+                                          pipeline_cache,
+                                          create_infos.len() as vk::uint32_t,
+                                          create_infos.as_ptr(),
+                                          self.allocation_callbacks(),
+                                          pipelines.as_mut_ptr());
+            pipelines.set_len(create_infos.len());
+            match err_code {
+                vk::Result::Success => Ok(pipelines),
+                _ => Err(err_code),
+            }
+        }
+    }
+
     pub fn create_buffer(&self, create_info: &vk::BufferCreateInfo) -> VkResult<vk::Buffer> {
         unsafe {
             let mut buffer = mem::uninitialized();
             let err_code = self.device_fn
-                .create_buffer(self.handle, create_info, ptr::null(), &mut buffer);
+                .create_buffer(self.handle, create_info, self.allocation_callbacks(), &mut buffer);
             match err_code {
                 vk::Result::Success => Ok(buffer),
                 _ => Err(err_code),
@@ -325,6 +712,100 @@ impl<'r> Device<'r> {
         }
     }
 
+    pub fn create_descriptor_set_layout(&self,
+                                        create_info: &vk::DescriptorSetLayoutCreateInfo)
+                                        -> VkResult<vk::DescriptorSetLayout> {
+        unsafe {
+            let mut layout = vk::DescriptorSetLayout::null();
+            let err_code = self.device_fn
+                .create_descriptor_set_layout(self.handle,
+                                              create_info,
+                                              self.allocation_callbacks(),
+                                              &mut layout);
+            match err_code {
+                vk::Result::Success => Ok(layout),
+                _ => Err(err_code),
+            }
+        }
+    }
+
+    pub fn create_descriptor_pool(&self,
+                                  create_info: &vk::DescriptorPoolCreateInfo)
+                                  -> VkResult<vk::DescriptorPool> {
+        unsafe {
+            let mut pool = vk::DescriptorPool::null();
+            let err_code = self.device_fn
+                .create_descriptor_pool(self.handle,
+                                        create_info,
+                                        self.allocation_callbacks(),
+                                        &mut pool);
+            match err_code {
+                vk::Result::Success => Ok(pool),
+                _ => Err(err_code),
+            }
+        }
+    }
+
+    pub fn reset_descriptor_pool(&self,
+                                 pool: vk::DescriptorPool,
+                                 flags: vk::DescriptorPoolResetFlags)
+                                 -> VkResult<()> {
+        unsafe {
+            let err_code = self.device_fn.reset_descriptor_pool(self.handle, pool, flags);
+            match err_code {
+                vk::Result::Success => Ok(()),
+                _ => Err(err_code),
+            }
+        }
+    }
+
+    pub fn allocate_descriptor_sets(&self,
+                                    create_info: &vk::DescriptorSetAllocateInfo)
+                                    -> VkResult<Vec<vk::DescriptorSet>> {
+        unsafe {
+            let mut sets = Vec::with_capacity(create_info.descriptor_set_count as vk::size_t);
+            let err_code = self.device_fn
+                .allocate_descriptor_sets(self.handle, create_info, sets.as_mut_ptr());
+            match err_code {
+                vk::Result::Success => {
+                    sets.set_len(create_info.descriptor_set_count as vk::size_t);
+                    Ok(sets)
+                }
+                _ => Err(err_code),
+            }
+        }
+    }
+
+    /// `pool` has to be created with `DESCRIPTOR_POOL_CREATE_FREE_DESCRIPTOR_SET_BIT`.
+    pub fn free_descriptor_sets(&self,
+                                pool: vk::DescriptorPool,
+                                descriptor_sets: &[vk::DescriptorSet])
+                                -> VkResult<()> {
+        unsafe {
+            let err_code = self.device_fn
+                .free_descriptor_sets(self.handle,
+                                      pool,
+                                      descriptor_sets.len() as vk::uint32_t,
+                                      descriptor_sets.as_ptr());
+            match err_code {
+                vk::Result::Success => Ok(()),
+                _ => Err(err_code),
+            }
+        }
+    }
+
+    pub fn update_descriptor_sets(&self,
+                                  descriptor_writes: &[vk::WriteDescriptorSet],
+                                  descriptor_copies: &[vk::CopyDescriptorSet]) {
+        unsafe {
+            self.device_fn.update_descriptor_sets(self.handle,
+                                                  descriptor_writes.len() as vk::uint32_t,
+                                                  descriptor_writes.as_ptr(),
+                                                  descriptor_copies.len() as vk::uint32_t,
+                                                  descriptor_copies.as_ptr());
+        }
+    }
+
     pub fn create_pipeline_layout(&self,
                                   create_info: &vk::PipelineLayoutCreateInfo)
                                   -> VkResult<vk::PipelineLayout> {
@@ -333,7 +814,7 @@ impl<'r> Device<'r> {
             let err_code = self.device_fn
                 .create_pipeline_layout(self.handle,
                                         create_info,
-                                        ptr::null(),
+                                        self.allocation_callbacks(),
                                         &mut pipeline_layout);
             match err_code {
                 vk::Result::Success => Ok(pipeline_layout),
@@ -341,25 +822,74 @@ impl<'r> Device<'r> {
             }
         }
     }
-    pub fn map_memory<T>(&self,
-                         memory: vk::DeviceMemory,
-                         offset: vk::DeviceSize,
-                         size: vk::DeviceSize,
-                         flags: vk::MemoryMapFlags)
-                         -> VkResult<&mut [T]> {
 
+    /// Maps `size` bytes of `memory` starting at `offset`, `size` may be `vk::VK_WHOLE_SIZE`
+    /// to map until the end of the allocation. The memory is unmapped again when the returned
+    /// `MappedMemory` is dropped.
+    ///
+    /// `T` has to be `Copy` because the device can write any bytes to the memory and nothing
+    /// is dropped when it is overwritten. The range is checked against the allocation size if
+    /// the allocation was recorded by memory tracking.
+    pub fn map_memory<T: Copy>(&self,
+                               memory: vk::DeviceMemory,
+                               offset: vk::DeviceSize,
+                               size: vk::DeviceSize,
+                               flags: vk::MemoryMapFlags)
+                               -> Result<MappedMemory<'_, T>, MapError> {
+        let allocation_size = self.allocation_size(memory);
+        let byte_size = match (allocation_size, size == vk::VK_WHOLE_SIZE) {
+            (Some(allocation_size), whole_size) => {
+                let byte_size = if whole_size {
+                    allocation_size.checked_sub(offset)
+                } else {
+                    offset.checked_add(size)
+                        .filter(|&end| end <= allocation_size)
+                        .map(|_| size)
+                };
+                match byte_size {
+                    Some(byte_size) => byte_size,
+                    None => {
+                        return Err(MapError::OutOfRange {
+                            offset,
+                            size,
+                            allocation_size,
+                        })
+                    }
+                }
+            }
+            (None, true) => return Err(MapError::UnknownAllocation(memory)),
+            (None, false) => size,
+        };
+        let element_size = mem::size_of::<T>() as vk::DeviceSize;
+        if element_size == 0 || byte_size % element_size != 0 {
+            return Err(MapError::SizeMismatch {
+                size: byte_size,
+                element_size,
+            });
+        }
         unsafe {
             let mut data: *mut () = mem::uninitialized();
             let err_code = self.device_fn
                 .map_memory(self.handle, memory, offset, size, flags, &mut data);
-            let x: *mut T = data as *mut T;
-            match err_code {
-                vk::Result::Success => {
-                    Ok(::std::slice::from_raw_parts_mut(x,
-                                                        size as vk::size_t / mem::size_of::<T>()))
-                }
-                _ => Err(err_code),
+            if err_code != vk::Result::Success {
+                return Err(MapError::VkError(err_code));
             }
+            if !(data as usize).is_multiple_of(mem::align_of::<T>()) {
+                self.unmap_memory(memory);
+                return Err(MapError::Misaligned {
+                    address: data as usize,
+                    align: mem::align_of::<T>(),
+                });
+            }
+            let end = offset + byte_size;
+            let reaches_end = size == vk::VK_WHOLE_SIZE || allocation_size == Some(end);
+            Ok(MappedMemory::from_raw(self,
+                                      memory,
+                                      offset,
+                                      byte_size,
+                                      reaches_end,
+                                      data as *mut T,
+                                      (byte_size / element_size) as vk::size_t))
         }
     }
 
@@ -369,13 +899,44 @@ impl<'r> Device<'r> {
         }
     }
 
+    pub fn flush_mapped_memory_ranges(&self, ranges: &[vk::MappedMemoryRange]) -> VkResult<()> {
+        unsafe {
+            let err_code = self.device_fn
+                .flush_mapped_memory_ranges(self.handle,
+                                            ranges.len() as vk::uint32_t,
+                                            ranges.as_ptr());
+            match err_code {
+                vk::Result::Success => Ok(()),
+                _ => Err(err_code),
+            }
+        }
+    }
+
+    pub fn invalidate_mapped_memory_ranges(&self,
+                                           ranges: &[vk::MappedMemoryRange])
+                                           -> VkResult<()> {
+        unsafe {
+            let err_code = self.device_fn
+                .invalidate_mapped_memory_ranges(self.handle,
+                                                 ranges.len() as vk::uint32_t,
+                                                 ranges.as_ptr());
+            match err_code {
+                vk::Result::Success => Ok(()),
+                _ => Err(err_code),
+            }
+        }
+    }
+
     pub fn create_framebuffer(&self,
                               create_info: &vk::FramebufferCreateInfo)
                               -> VkResult<vk::Framebuffer> {
         unsafe {
             let mut framebuffer = mem::uninitialized();
             let err_code = self.device_fn
-                .create_framebuffer(self.handle, create_info, ptr::null(), &mut framebuffer);
+                .create_framebuffer(self.handle,
+                                    create_info,
+                                    self.allocation_callbacks(),
+                                    &mut framebuffer);
             match err_code {
                 vk::Result::Success => Ok(framebuffer),
                 _ => Err(err_code),
@@ -423,7 +984,10 @@ impl<'r> Device<'r> {
         unsafe {
             let mut renderpass = mem::uninitialized();
             let err_code = self.device_fn
-                .create_render_pass(self.handle, create_info, ptr::null(), &mut renderpass);
+                .create_render_pass(self.handle,
+                                    create_info,
+                                    self.allocation_callbacks(),
+                                    &mut renderpass);
             match err_code {
                 vk::Result::Success => Ok(renderpass),
                 _ => Err(err_code),
@@ -445,6 +1009,34 @@ impl<'r> Device<'r> {
         }
     }
 
+    /// Begins a secondary command buffer that continues `subpass` of `render_pass`.
+    /// `framebuffer` may be `vk::Framebuffer::null()` if it is not known at record time.
+    pub fn begin_secondary_command_buffer(&self,
+                                          command_buffer: vk::CommandBuffer,
+                                          flags: vk::CommandBufferUsageFlags,
+                                          render_pass: vk::RenderPass,
+                                          subpass: vk::uint32_t,
+                                          framebuffer: vk::Framebuffer)
+                                          -> VkResult<()> {
+        let inheritance_info = vk::CommandBufferInheritanceInfo {
+            s_type: vk::StructureType::CommandBufferInheritanceInfo,
+            p_next: ptr::null(),
+            render_pass,
+            subpass,
+            framebuffer,
+            occlusion_query_enable: 0,
+            query_flags: vk::QueryControlFlags::empty(),
+            pipeline_statistics: vk::QueryPipelineStatisticFlags::empty(),
+        };
+        let begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::CommandBufferBeginInfo,
+            p_next: ptr::null(),
+            flags: flags | vk::COMMAND_BUFFER_USAGE_RENDER_PASS_CONTINUE_BIT,
+            p_inheritance_info: &inheritance_info,
+        };
+        self.begin_command_buffer(command_buffer, &begin_info)
+    }
+
     pub fn end_command_buffer(&self, command_buffer: vk::CommandBuffer) -> VkResult<()> {
         unsafe {
             let err_code = self.device_fn
@@ -456,6 +1048,18 @@ impl<'r> Device<'r> {
         }
     }
 
+    /// Returns true if `fence` is signaled.
+    pub fn get_fence_status(&self, fence: vk::Fence) -> VkResult<bool> {
+        unsafe {
+            let err_code = self.device_fn.get_fence_status(self.handle, fence);
+            match err_code {
+                vk::Result::Success => Ok(true),
+                vk::Result::NotReady => Ok(false),
+                _ => Err(err_code),
+            }
+        }
+    }
+
     pub fn wait_for_fences(&self,
                            fences: &[vk::Fence],
                            wait_all: bool,
@@ -485,20 +1089,26 @@ impl<'r> Device<'r> {
         }
     }
 
+    /// Returns true if the swapchain is suboptimal, the images were presented anyway.
     pub fn queue_present_khr(&self,
                              queue: vk::Queue,
                              create_info: &vk::PresentInfoKHR)
-                             -> VkResult<()> {
+                             -> VkResult<bool> {
         unsafe {
             let err_code = self.device_fn
                 .queue_present_khr(queue, create_info);
             match err_code {
-                vk::Result::Success => Ok(()),
+                vk::Result::Success => Ok(false),
+                vk::Result::SuboptimalKhr => Ok(true),
                 _ => Err(err_code),
             }
         }
     }
 
+    /// Unchecked submission of raw `vk::SubmitInfo`s. Nothing verifies that the command buffers
+    /// have finished recording, the caller has to make sure they are all in the executable
+    /// state. Prefer `CommandBufferRecorder<Executable>::submit`, which can only be called once
+    /// recording has ended.
     pub fn queue_submit(&self,
                         queue: vk::Queue,
                         submits: &[vk::SubmitInfo],
@@ -523,7 +1133,10 @@ impl<'r> Device<'r> {
         unsafe {
             let mut image_view = mem::uninitialized();
             let err_code = self.device_fn
-                .create_image_view(self.handle, create_info, ptr::null(), &mut image_view);
+                .create_image_view(self.handle,
+                                   create_info,
+                                   self.allocation_callbacks(),
+                                   &mut image_view);
             match err_code {
                 vk::Result::Success => Ok(image_view),
                 _ => Err(err_code),
@@ -565,13 +1178,126 @@ impl<'r> Device<'r> {
         }
     }
 
+    pub fn free_command_buffers(&self,
+                                command_pool: vk::CommandPool,
+                                command_buffers: &[vk::CommandBuffer]) {
+        unsafe {
+            self.device_fn.free_command_buffers(self.handle,
+                                                command_pool,
+                                                command_buffers.len() as vk::uint32_t,
+                                                command_buffers.as_ptr());
+        }
+    }
+
+    /// Records `record` into a new primary command buffer from `command_pool`, submits it to
+    /// `queue` and waits until it has executed. Meant for setup work such as copies and
+    /// layout transitions.
+    pub fn submit_once<F>(&self,
+                          queue: vk::Queue,
+                          command_pool: vk::CommandPool,
+                          record: F)
+                          -> VkResult<()>
+        where F: FnOnce(vk::CommandBuffer)
+    {
+        let (fence, command_buffer) = self.submit_once_async(queue, command_pool, record)?;
+        let result = self.wait_for_fences(&[fence], true, u64::MAX);
+        // If the wait failed the command buffer may still be pending, so it is only freed once
+        // the device is idle, and leaked along with the fence if that fails as well.
+        if result.is_ok() || self.device_wait_idle().is_ok() {
+            self.destroy_fence(fence);
+            self.free_command_buffers(command_pool, &[command_buffer]);
+        }
+        result
+    }
+
+    /// Like `submit_once`, but returns right after submitting. Once the returned fence has
+    /// signaled, the caller destroys it and frees the command buffer from `command_pool`.
+    pub fn submit_once_async<F>(&self,
+                                queue: vk::Queue,
+                                command_pool: vk::CommandPool,
+                                record: F)
+                                -> VkResult<(vk::Fence, vk::CommandBuffer)>
+        where F: FnOnce(vk::CommandBuffer)
+    {
+        let allocate_info = vk::CommandBufferAllocateInfo {
+            s_type: vk::StructureType::CommandBufferAllocateInfo,
+            p_next: ptr::null(),
+            command_pool,
+            level: vk::CommandBufferLevel::Primary,
+            command_buffer_count: 1,
+        };
+        let command_buffer = self.allocate_command_buffers(&allocate_info)?[0];
+        let begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::CommandBufferBeginInfo,
+            p_next: ptr::null(),
+            flags: vk::COMMAND_BUFFER_USAGE_ONE_TIME_SUBMIT_BIT,
+            p_inheritance_info: ptr::null(),
+        };
+        let recorded = self.begin_command_buffer(command_buffer, &begin_info).and_then(|_| {
+            record(command_buffer);
+            self.end_command_buffer(command_buffer)
+        });
+        if let Err(err) = recorded {
+            self.free_command_buffers(command_pool, &[command_buffer]);
+            return Err(err);
+        }
+        let fence_info = vk::FenceCreateInfo {
+            s_type: vk::StructureType::FenceCreateInfo,
+            p_next: ptr::null(),
+            flags: vk::FenceCreateFlags::empty(),
+        };
+        let fence = match self.create_fence(&fence_info) {
+            Ok(fence) => fence,
+            Err(err) => {
+                self.free_command_buffers(command_pool, &[command_buffer]);
+                return Err(err);
+            }
+        };
+        let submit_info = vk::SubmitInfo {
+            s_type: vk::StructureType::SubmitInfo,
+            p_next: ptr::null(),
+            wait_semaphore_count: 0,
+            p_wait_semaphores: ptr::null(),
+            p_wait_dst_stage_mask: ptr::null(),
+            command_buffer_count: 1,
+            p_command_buffers: &command_buffer,
+            signal_semaphore_count: 0,
+            p_signal_semaphores: ptr::null(),
+        };
+        match self.queue_submit(queue, &[submit_info], fence) {
+            Ok(()) => Ok((fence, command_buffer)),
+            Err(err) => {
+                self.destroy_fence(fence);
+                self.free_command_buffers(command_pool, &[command_buffer]);
+                Err(err)
+            }
+        }
+    }
+
+    pub fn reset_command_pool(&self,
+                              command_pool: vk::CommandPool,
+                              flags: vk::CommandPoolResetFlags)
+                              -> VkResult<()> {
+        unsafe {
+            let err_code = self.device_fn
+                .reset_command_pool(self.handle, command_pool, flags);
+            match err_code {
+                vk::Result::Success => Ok(()),
+                _ => Err(err_code),
+            }
+        }
+    }
+
     pub fn create_command_pool(&self,
                                create_info: &vk::CommandPoolCreateInfo)
                                -> VkResult<vk::CommandPool> {
         unsafe {
             let mut pool = mem::uninitialized();
             let err_code = self.device_fn
-                .create_command_pool(self.handle, create_info, ptr::null(), &mut pool);
+                .create_command_pool(self.handle,
+                                     create_info,
+                                     self.allocation_callbacks(),
+                                     &mut pool);
             match err_code {
                 vk::Result::Success => Ok(pool),
                 _ => Err(err_code),
@@ -585,7 +1311,10 @@ impl<'r> Device<'r> {
         unsafe {
             let mut swapchain = mem::uninitialized();
             let err_code = self.device_fn
-                .create_swapchain_khr(self.handle, create_info, ptr::null(), &mut swapchain);
+                .create_swapchain_khr(self.handle,
+                                      create_info,
+                                      self.allocation_callbacks(),
+                                      &mut swapchain);
             match err_code {
                 vk::Result::Success => Ok(swapchain),
                 _ => Err(err_code),
@@ -597,7 +1326,7 @@ impl<'r> Device<'r> {
         unsafe {
             let mut image = mem::uninitialized();
             let err_code = self.device_fn
-                .create_image(self.handle, create_info, ptr::null(), &mut image);
+                .create_image(self.handle, create_info, self.allocation_callbacks(), &mut image);
             match err_code {
                 vk::Result::Success => Ok(image),
                 _ => Err(err_code),
@@ -623,15 +1352,60 @@ impl<'r> Device<'r> {
         }
     }
 
+    pub fn get_image_sparse_memory_requirements(&self,
+                                                image: vk::Image)
+                                                -> Vec<vk::SparseImageMemoryRequirements> {
+        unsafe {
+            let mut count = 0;
+            self.device_fn
+                .get_image_sparse_memory_requirements(self.handle,
+                                                      image,
+                                                      &mut count,
+                                                      ptr::null_mut());
+            let mut v = Vec::with_capacity(count as vk::size_t);
+            self.device_fn
+                .get_image_sparse_memory_requirements(self.handle,
+                                                      image,
+                                                      &mut count,
+                                                      v.as_mut_ptr());
+            v.set_len(count as vk::size_t);
+            v
+        }
+    }
+
+    pub fn queue_bind_sparse(&self,
+                             queue: vk::Queue,
+                             bind_infos: &[vk::BindSparseInfo],
+                             fence: vk::Fence)
+                             -> VkResult<()> {
+        unsafe {
+            let err_code = self.device_fn
+                .queue_bind_sparse(queue,
+                                   bind_infos.len() as vk::uint32_t,
+                                   bind_infos.as_ptr(),
+                                   fence);
+            match err_code {
+                vk::Result::Success => Ok(()),
+                _ => Err(err_code),
+            }
+        }
+    }
+
     pub fn allocate_memory(&self,
                            create_info: &vk::MemoryAllocateInfo)
                            -> VkResult<vk::DeviceMemory> {
         unsafe {
             let mut memory = mem::uninitialized();
             let err_code = self.device_fn
-                .allocate_memory(self.handle, create_info, ptr::null(), &mut memory);
+                .allocate_memory(self.handle,
+                                 create_info,
+                                 self.allocation_callbacks(),
+                                 &mut memory);
             match err_code {
-                vk::Result::Success => Ok(memory),
+                vk::Result::Success => {
+                    self.record_allocation(memory, create_info);
+                    Ok(memory)
+                }
                 _ => Err(err_code),
             }
         }
@@ -643,7 +1417,10 @@ impl<'r> Device<'r> {
         unsafe {
             let mut shader = mem::uninitialized();
             let err_code = self.device_fn
-                .create_shader_module(self.handle, create_info, ptr::null(), &mut shader);
+                .create_shader_module(self.handle,
+                                      create_info,
+                                      self.allocation_callbacks(),
+                                      &mut shader);
             match err_code {
                 vk::Result::Success => Ok(shader),
                 _ => Err(err_code),
@@ -651,11 +1428,27 @@ impl<'r> Device<'r> {
         }
     }
 
+    /// Creates a shader module from SPIR-V words, such as those returned by `util::read_spv`,
+    /// after checking the header.
+    pub fn create_shader_module_from_words(&self,
+                                           code: &[vk::uint32_t])
+                                           -> Result<vk::ShaderModule, ShaderModuleError> {
+        check_spirv_header(code)?;
+        let create_info = vk::ShaderModuleCreateInfo {
+            s_type: vk::StructureType::ShaderModuleCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            code_size: mem::size_of_val(code),
+            p_code: code.as_ptr(),
+        };
+        self.create_shader_module(&create_info).map_err(ShaderModuleError::VkError)
+    }
+
     pub fn create_fence(&self, create_info: &vk::FenceCreateInfo) -> VkResult<vk::Fence> {
         unsafe {
             let mut fence = mem::uninitialized();
             let err_code = self.device_fn
-                .create_fence(self.handle, create_info, ptr::null(), &mut fence);
+                .create_fence(self.handle, create_info, self.allocation_callbacks(), &mut fence);
             match err_code {
                 vk::Result::Success => Ok(fence),
                 _ => Err(err_code),
@@ -692,4 +1485,65 @@ impl<'r> Device<'r> {
             }
         }
     }
+
+    fn record_allocation(&self, memory: vk::DeviceMemory, create_info: &vk::MemoryAllocateInfo) {
+        if !self.tracking_enabled.load(Ordering::Acquire) {
+            return;
+        }
+        let crossed = {
+            let mut allocations = self.allocations.lock().unwrap();
+            allocations.insert(memory,
+                               (create_info.allocation_size, create_info.memory_type_index));
+            match *self.memory_tracker.lock().unwrap() {
+                Some(ref mut tracker) => {
+                    tracker.record_allocation(create_info.memory_type_index,
+                                              create_info.allocation_size)
+                }
+                None => None,
+            }
+        };
+        // The hook is called without holding any lock, so it can allocate or free memory itself.
+        if let Some(heap_usage) = crossed {
+            let hook = self.memory_warning
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map(|(_, hook)| hook.clone());
+            if let Some(hook) = hook {
+                hook(&heap_usage);
+            }
+        }
+    }
+}
+
+/// The checks `create_shader_module_from_words` makes before the code is passed to the driver.
+fn check_spirv_header(code: &[vk::uint32_t]) -> Result<(), ShaderModuleError> {
+    if code.len() < util::SPIRV_HEADER_WORDS {
+        return Err(ShaderModuleError::TooShort(code.len()));
+    }
+    if code[0] != util::SPIRV_MAGIC {
+        return Err(ShaderModuleError::BadMagic(code[0]));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use util;
+    use super::{check_spirv_header, ShaderModuleError};
+
+    #[test]
+    fn spirv_headers_are_checked_before_the_driver_sees_them() {
+        let mut code = vec![util::SPIRV_MAGIC, 0x0001_0000, 0, 1, 0];
+        assert!(check_spirv_header(&code).is_ok());
+        match check_spirv_header(&code[..4]) {
+            Err(ShaderModuleError::TooShort(4)) => {}
+            other => panic!("expected TooShort(4), got {:?}", other),
+        }
+        code[0] = util::SPIRV_MAGIC.swap_bytes();
+        match check_spirv_header(&code) {
+            Err(ShaderModuleError::BadMagic(magic)) => assert_eq!(magic, code[0]),
+            other => panic!("expected BadMagic, got {:?}", other),
+        }
+    }
 }
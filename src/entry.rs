@@ -5,6 +5,8 @@ use vk;
 use instance::Instance;
 use shared_library::dynamic_library::DynamicLibrary;
 use std::path::Path;
+use std::sync::Arc;
+use allocator::{self, AllocationCallbacks, HostAllocator};
 #[cfg(windows)]
 fn get_path() -> &'static Path {
     Path::new("vulkan-1.dll")
@@ -68,17 +70,36 @@ impl Entry {
 
     pub fn create_instance(&self,
                            create_info: &vk::InstanceCreateInfo)
-                           -> Result<Instance, InstanceError> {
+                           -> Result<Instance<'_>, InstanceError> {
+        self.create_instance_with_callbacks(create_info, None)
+    }
+
+    /// Creates an instance that allocates host memory through `allocator`. Devices created
+    /// from the instance use the same allocator unless they are given their own.
+    pub fn create_instance_with_allocator(&self,
+                                          create_info: &vk::InstanceCreateInfo,
+                                          allocator: Arc<dyn HostAllocator>)
+                                          -> Result<Instance<'_>, InstanceError> {
+        self.create_instance_with_callbacks(create_info,
+                                            Some(Arc::new(AllocationCallbacks::new(allocator))))
+    }
+
+    fn create_instance_with_callbacks(&self,
+                                      create_info: &vk::InstanceCreateInfo,
+                                      allocation_callbacks: Option<Arc<AllocationCallbacks>>)
+                                      -> Result<Instance<'_>, InstanceError> {
         unsafe {
             let mut instance: vk::Instance = mem::uninitialized();
-            let err_code = self.entry_fn.create_instance(create_info, ptr::null(), &mut instance);
+            let err_code = self.entry_fn.create_instance(create_info,
+                                                         allocator::as_ptr(&allocation_callbacks),
+                                                         &mut instance);
             if err_code != vk::Result::Success {
                 return Err(InstanceError::VkError(err_code));
             }
             let instance_fn = vk::InstanceFn::load(|name| unsafe {
                     mem::transmute(self.static_fn.get_instance_proc_addr(instance, name.as_ptr()))
                 }).map_err(|err| InstanceError::LoadError(err))?;
-            Ok(Instance::from_raw(instance, instance_fn))
+            Ok(Instance::from_raw(instance, instance_fn, allocation_callbacks))
         }
     }
 
@@ -0,0 +1,159 @@
+use prelude::*;
+use std::ptr;
+use vk;
+use device::Device;
+
+/// The synchronization objects of one frame in flight.
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    pub index: usize,
+    /// Signal this with the submission that renders the frame.
+    pub fence: vk::Fence,
+    /// Pass this to `acquire_next_image_khr` and wait on it before writing the image.
+    pub image_available: vk::Semaphore,
+    /// Signal this with the submission and wait on it in `queue_present_khr`.
+    pub render_finished: vk::Semaphore,
+}
+
+struct FrameState {
+    frame: Frame,
+    // True between `end_frame` and the next wait on the fence, an unsubmitted fence would
+    // never signal.
+    submitted: bool,
+}
+
+/// Owns a fence and an image available and render finished semaphore for each of N frames in
+/// flight. A frame looks like:
+///
+/// 1. `begin_frame` waits until the frame's previous submission has finished.
+/// 2. Acquire an image with `image_available`, then call `image_acquired`.
+/// 3. Submit with `fence` and `render_finished`, present, and call `end_frame`.
+///
+/// If no image could be acquired, the frame can simply be started again with `begin_frame`.
+pub struct FrameSync<'a> {
+    device: &'a Device<'a>,
+    frames: Vec<FrameState>,
+    current: usize,
+    // For every swapchain image, the frame that last rendered to it.
+    image_frames: Vec<Option<usize>>,
+}
+
+impl<'a> FrameSync<'a> {
+    /// Panics if `frame_count` is 0.
+    pub fn new(device: &'a Device<'a>, frame_count: usize) -> VkResult<FrameSync<'a>> {
+        assert!(frame_count > 0, "a FrameSync needs at least one frame");
+        let mut frame_sync = FrameSync {
+            device,
+            frames: Vec::with_capacity(frame_count),
+            current: 0,
+            image_frames: Vec::new(),
+        };
+        let fence_info = vk::FenceCreateInfo {
+            s_type: vk::StructureType::FenceCreateInfo,
+            p_next: ptr::null(),
+            flags: vk::FenceCreateFlags::empty(),
+        };
+        let semaphore_info = vk::SemaphoreCreateInfo {
+            s_type: vk::StructureType::SemaphoreCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+        };
+        // Objects created so far are destroyed by `drop` if a later creation fails.
+        for index in 0..frame_count {
+            let fence = device.create_fence(&fence_info)?;
+            let image_available = match device.create_semaphore(&semaphore_info) {
+                Ok(semaphore) => semaphore,
+                Err(err) => {
+                    device.destroy_fence(fence);
+                    return Err(err);
+                }
+            };
+            let render_finished = match device.create_semaphore(&semaphore_info) {
+                Ok(semaphore) => semaphore,
+                Err(err) => {
+                    device.destroy_fence(fence);
+                    device.destroy_semaphore(image_available);
+                    return Err(err);
+                }
+            };
+            frame_sync.frames.push(FrameState {
+                frame: Frame {
+                    index,
+                    fence,
+                    image_available,
+                    render_finished,
+                },
+                submitted: false,
+            });
+        }
+        Ok(frame_sync)
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn current_frame(&self) -> Frame {
+        self.frames[self.current].frame
+    }
+
+    /// Waits for the previous submission of the current frame and resets its fence.
+    pub fn begin_frame(&mut self) -> VkResult<Frame> {
+        self.wait_frame(self.current)?;
+        Ok(self.current_frame())
+    }
+
+    /// Waits for the frame that last rendered to `image_index`, which can be another frame
+    /// than the current one if the swapchain hands out images out of order.
+    pub fn image_acquired(&mut self, image_index: vk::uint32_t) -> VkResult<()> {
+        let image_index = image_index as usize;
+        if image_index >= self.image_frames.len() {
+            self.image_frames.resize(image_index + 1, None);
+        }
+        if let Some(frame) = self.image_frames[image_index] {
+            self.wait_frame(frame)?;
+        }
+        self.image_frames[image_index] = Some(self.current);
+        Ok(())
+    }
+
+    /// Marks the current frame as submitted with its fence and moves on to the next one.
+    pub fn end_frame(&mut self) {
+        self.frames[self.current].submitted = true;
+        self.current = (self.current + 1) % self.frames.len();
+    }
+
+    /// Forgets which frame used which image, e.g. after the swapchain was recreated.
+    pub fn reset_images(&mut self) {
+        self.image_frames.clear();
+    }
+
+    fn wait_frame(&mut self, index: usize) -> VkResult<()> {
+        let state = &mut self.frames[index];
+        if state.submitted {
+            self.device.wait_for_fences(&[state.frame.fence], true, u64::MAX)?;
+            self.device.reset_fences(&[state.frame.fence])?;
+            state.submitted = false;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for FrameSync<'a> {
+    /// Waits for every submitted frame before destroying the fences and semaphores.
+    fn drop(&mut self) {
+        let fences: Vec<vk::Fence> = self.frames
+            .iter()
+            .filter(|state| state.submitted)
+            .map(|state| state.frame.fence)
+            .collect();
+        if !fences.is_empty() {
+            let _ = self.device.wait_for_fences(&fences, true, u64::MAX);
+        }
+        for state in self.frames.drain(..) {
+            self.device.destroy_fence(state.frame.fence);
+            self.device.destroy_semaphore(state.frame.image_available);
+            self.device.destroy_semaphore(state.frame.render_finished);
+        }
+    }
+}
@@ -0,0 +1,959 @@
+use std::cmp::{self, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::ptr;
+use vk;
+use barrier::{ResourceTracker, Usage};
+use device::Device;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImageId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BufferId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PassId(usize);
+
+#[derive(Debug)]
+pub enum GraphError {
+    /// The attachments of the pass don't all have the same extent.
+    ExtentMismatch(PassId),
+    NoCompatibleMemoryType,
+    VkError(vk::Result),
+}
+
+impl From<vk::Result> for GraphError {
+    fn from(err: vk::Result) -> GraphError {
+        GraphError::VkError(err)
+    }
+}
+
+/// An image that is created outside of the graph, such as a swapchain image.
+#[derive(Debug, Clone)]
+pub struct ImportedImage {
+    pub image: vk::Image,
+    /// Only needed if the image is used as an attachment.
+    pub view: vk::ImageView,
+    pub format: vk::Format,
+    pub extent: vk::Extent2D,
+    /// Every mip level and array layer of the image is transitioned.
+    pub mip_levels: u32,
+    pub array_layers: u32,
+    /// The layout the image is in when the graph is executed.
+    pub initial_layout: vk::ImageLayout,
+    /// The stages the first access has to wait for, e.g. the stages the submission waits on the
+    /// image available semaphore in.
+    pub wait_stages: vk::PipelineStageFlags,
+    /// How the image is used after the graph, e.g. `Usage::Present`.
+    pub final_usage: Option<Usage>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Resource {
+    Image(ImageId),
+    Buffer(BufferId),
+}
+
+struct ImageNode {
+    format: vk::Format,
+    extent: vk::Extent2D,
+    imported: Option<ImportedImage>,
+}
+
+struct BufferNode {
+    buffer: vk::Buffer,
+    size: vk::DeviceSize,
+    final_usage: Option<Usage>,
+}
+
+struct PassNode {
+    name: String,
+    color_attachments: Vec<(ImageId, Option<[f32; 4]>)>,
+    depth_attachment: Option<(ImageId, Option<(f32, u32)>)>,
+    // Accesses outside of attachments, the flag is true for writes.
+    accesses: Vec<(Resource, Usage, bool)>,
+}
+
+impl PassNode {
+    fn has_attachments(&self) -> bool {
+        !self.color_attachments.is_empty() || self.depth_attachment.is_some()
+    }
+
+    /// Every access of the pass, attachments count as writes.
+    fn all_accesses(&self) -> Vec<(Resource, Usage, bool)> {
+        let mut accesses: Vec<_> = self.color_attachments
+            .iter()
+            .map(|&(image, _)| (Resource::Image(image), Usage::ColorAttachment, true))
+            .collect();
+        if let Some((image, _)) = self.depth_attachment {
+            accesses.push((Resource::Image(image), Usage::DepthStencilAttachment, true));
+        }
+        accesses.extend(self.accesses.iter().cloned());
+        accesses
+    }
+
+    fn remove(&mut self, resource: Resource) {
+        self.color_attachments.retain(|&(image, _)| Resource::Image(image) != resource);
+        if self.depth_attachment.is_some_and(|(image, _)| Resource::Image(image) == resource) {
+            self.depth_attachment = None;
+        }
+        self.accesses.retain(|&(other, _, _)| other != resource);
+    }
+}
+
+/// Declares the resources of one pass. Declaring a resource again replaces the earlier
+/// declaration.
+pub struct PassBuilder<'g> {
+    id: PassId,
+    pass: &'g mut PassNode,
+}
+
+impl<'g> PassBuilder<'g> {
+    pub fn id(&self) -> PassId {
+        self.id
+    }
+
+    /// Renders to `image`, clearing it first if `clear` is given.
+    pub fn color_attachment(self, image: ImageId, clear: Option<[f32; 4]>) -> PassBuilder<'g> {
+        self.pass.remove(Resource::Image(image));
+        self.pass.color_attachments.push((image, clear));
+        self
+    }
+
+    /// Uses `image` as the depth attachment, clearing it to `(depth, stencil)` first if `clear`
+    /// is given.
+    pub fn depth_attachment(self,
+                            image: ImageId,
+                            clear: Option<(f32, u32)>)
+                            -> PassBuilder<'g> {
+        self.pass.remove(Resource::Image(image));
+        self.pass.depth_attachment = Some((image, clear));
+        self
+    }
+
+    pub fn read_image(self, image: ImageId, usage: Usage) -> PassBuilder<'g> {
+        self.access(Resource::Image(image), usage, false)
+    }
+
+    pub fn write_image(self, image: ImageId, usage: Usage) -> PassBuilder<'g> {
+        self.access(Resource::Image(image), usage, true)
+    }
+
+    pub fn read_buffer(self, buffer: BufferId, usage: Usage) -> PassBuilder<'g> {
+        self.access(Resource::Buffer(buffer), usage, false)
+    }
+
+    pub fn write_buffer(self, buffer: BufferId, usage: Usage) -> PassBuilder<'g> {
+        self.access(Resource::Buffer(buffer), usage, true)
+    }
+
+    fn access(self, resource: Resource, usage: Usage, write: bool) -> PassBuilder<'g> {
+        self.pass.remove(resource);
+        self.pass.accesses.push((resource, usage, write));
+        self
+    }
+}
+
+/// Describes a frame as passes that read and write images and buffers.
+///
+/// `compile` orders the passes so that every access to a resource happens after the last write
+/// to it that was added before the access, and every write after the reads added since the
+/// previous write. It then creates the transient images, letting images whose lifetimes don't
+/// overlap share memory, and creates a render pass and framebuffer for every pass with
+/// attachments. `CompiledGraph::execute` then records the passes with the barriers between
+/// them.
+#[derive(Default)]
+pub struct RenderGraph {
+    images: Vec<ImageNode>,
+    buffers: Vec<BufferNode>,
+    passes: Vec<PassNode>,
+}
+
+impl RenderGraph {
+    pub fn new() -> RenderGraph {
+        RenderGraph::default()
+    }
+
+    /// Declares a 2D image with a single mip level that only exists during the graph. It is
+    /// only created if a pass uses it.
+    pub fn create_image(&mut self, format: vk::Format, extent: vk::Extent2D) -> ImageId {
+        self.images.push(ImageNode {
+            format,
+            extent,
+            imported: None,
+        });
+        ImageId(self.images.len() - 1)
+    }
+
+    pub fn import_image(&mut self, image: ImportedImage) -> ImageId {
+        self.images.push(ImageNode {
+            format: image.format,
+            extent: image.extent.clone(),
+            imported: Some(image),
+        });
+        ImageId(self.images.len() - 1)
+    }
+
+    pub fn import_buffer(&mut self,
+                         buffer: vk::Buffer,
+                         size: vk::DeviceSize,
+                         final_usage: Option<Usage>)
+                         -> BufferId {
+        self.buffers.push(BufferNode {
+            buffer,
+            size,
+            final_usage,
+        });
+        BufferId(self.buffers.len() - 1)
+    }
+
+    pub fn add_pass<'g>(&'g mut self, name: &str) -> PassBuilder<'g> {
+        self.passes.push(PassNode {
+            name: name.to_owned(),
+            color_attachments: Vec::new(),
+            depth_attachment: None,
+            accesses: Vec::new(),
+        });
+        let id = PassId(self.passes.len() - 1);
+        PassBuilder {
+            id,
+            pass: self.passes.last_mut().unwrap(),
+        }
+    }
+
+    /// Orders the passes and creates the objects they need. Everything created so far is
+    /// destroyed again if this fails.
+    pub fn compile(self,
+                   device: &Device,
+                   memory_properties: &vk::PhysicalDeviceMemoryProperties)
+                   -> Result<CompiledGraph, GraphError> {
+        let order = self.schedule();
+        let mut first_use = vec![None; self.images.len()];
+        let mut last_use = vec![None; self.images.len()];
+        let mut usage_flags = vec![vk::ImageUsageFlags::empty(); self.images.len()];
+        for (position, &index) in order.iter().enumerate() {
+            for (resource, usage, _) in self.passes[index].all_accesses() {
+                if let Resource::Image(ImageId(image)) = resource {
+                    first_use[image] = first_use[image].or(Some(position));
+                    last_use[image] = Some(position);
+                    usage_flags[image] |= image_usage_flags(usage);
+                }
+            }
+        }
+        let mut graph = CompiledGraph {
+            order,
+            passes: self.passes,
+            images: Vec::with_capacity(self.images.len()),
+            buffers: self.buffers,
+            memories: Vec::new(),
+            targets: Vec::new(),
+            first_use,
+            last_use,
+            aliases: vec![None; self.images.len()],
+        };
+        match graph.create_objects(device, memory_properties, self.images, usage_flags) {
+            Ok(()) => Ok(graph),
+            Err(err) => {
+                graph.destroy(device);
+                Err(err)
+            }
+        }
+    }
+
+    /// Kahn's algorithm, ties are broken by the order the passes were added in.
+    fn schedule(&self) -> Vec<usize> {
+        let mut successors = vec![Vec::new(); self.passes.len()];
+        let mut predecessor_counts = vec![0; self.passes.len()];
+        {
+            let mut add_edge = |from: usize, to: usize| {
+                successors[from].push(to);
+                predecessor_counts[to] += 1;
+            };
+            // The last writer of every resource and the readers added since then.
+            let mut last_writers: HashMap<Resource, usize> = HashMap::new();
+            let mut readers: HashMap<Resource, Vec<usize>> = HashMap::new();
+            for (index, pass) in self.passes.iter().enumerate() {
+                for (resource, _, write) in pass.all_accesses() {
+                    if let Some(&writer) = last_writers.get(&resource) {
+                        add_edge(writer, index);
+                    }
+                    if write {
+                        for reader in readers.remove(&resource).unwrap_or_default() {
+                            add_edge(reader, index);
+                        }
+                        last_writers.insert(resource, index);
+                    } else {
+                        readers.entry(resource).or_default().push(index);
+                    }
+                }
+            }
+        }
+        let mut ready: BinaryHeap<Reverse<usize>> = predecessor_counts.iter()
+            .enumerate()
+            .filter(|&(_, &count)| count == 0)
+            .map(|(index, _)| Reverse(index))
+            .collect();
+        let mut order = Vec::with_capacity(self.passes.len());
+        while let Some(Reverse(index)) = ready.pop() {
+            order.push(index);
+            for &successor in &successors[index] {
+                predecessor_counts[successor] -= 1;
+                if predecessor_counts[successor] == 0 {
+                    ready.push(Reverse(successor));
+                }
+            }
+        }
+        // Every edge points to a pass that was added later, so there are no cycles.
+        debug_assert_eq!(order.len(), self.passes.len());
+        order
+    }
+}
+
+struct GraphImage {
+    image: vk::Image,
+    view: vk::ImageView,
+    format: vk::Format,
+    extent: vk::Extent2D,
+    imported: Option<ImportedImage>,
+}
+
+struct PassTarget {
+    render_pass: vk::RenderPass,
+    framebuffer: vk::Framebuffer,
+    extent: vk::Extent2D,
+    clear_values: Vec<vk::ClearValue>,
+}
+
+// Transient images that share one allocation, in the order of their lifetimes.
+struct MemorySlot {
+    images: Vec<usize>,
+    size: vk::DeviceSize,
+    memory_type_bits: vk::uint32_t,
+    last_use: usize,
+}
+
+/// The objects created for a `RenderGraph`. Imported resources are assumed to be synchronized
+/// with earlier work by the caller, e.g. with semaphores or fences. The graph must not be
+/// executed again until the device has finished the previous execution, and has to be
+/// destroyed with `destroy`.
+pub struct CompiledGraph {
+    order: Vec<usize>,
+    passes: Vec<PassNode>,
+    images: Vec<GraphImage>,
+    buffers: Vec<BufferNode>,
+    memories: Vec<vk::DeviceMemory>,
+    targets: Vec<Option<PassTarget>>,
+    first_use: Vec<Option<usize>>,
+    last_use: Vec<Option<usize>>,
+    // The image that used the memory of a transient image before it.
+    aliases: Vec<Option<usize>>,
+}
+
+impl CompiledGraph {
+    /// The passes in the order they are executed.
+    pub fn order(&self) -> Vec<PassId> {
+        self.order.iter().map(|&index| PassId(index)).collect()
+    }
+
+    pub fn pass_name(&self, pass: PassId) -> &str {
+        &self.passes[pass.0].name
+    }
+
+    /// The render pass a pass with attachments records its draws in.
+    pub fn render_pass(&self, pass: PassId) -> Option<vk::RenderPass> {
+        self.targets[pass.0].as_ref().map(|target| target.render_pass)
+    }
+
+    /// `null()` for transient images that no pass uses.
+    pub fn image(&self, image: ImageId) -> vk::Image {
+        self.images[image.0].image
+    }
+
+    pub fn image_view(&self, image: ImageId) -> vk::ImageView {
+        self.images[image.0].view
+    }
+
+    /// Records every pass into `command_buffer` with the barriers it needs. `record` is called
+    /// once per pass, inside the render pass for passes with attachments.
+    pub fn execute<F>(&self, device: &Device, command_buffer: vk::CommandBuffer, mut record: F)
+        where F: FnMut(PassId, vk::CommandBuffer)
+    {
+        let mut tracker = ResourceTracker::new();
+        for image in &self.images {
+            if let Some(ref imported) = image.imported {
+                tracker.register_image_after(image.image,
+                                             imported.mip_levels,
+                                             imported.array_layers,
+                                             imported.initial_layout,
+                                             imported.wait_stages);
+            }
+        }
+        for buffer in &self.buffers {
+            tracker.register_buffer(buffer.buffer, buffer.size);
+        }
+        for (position, &index) in self.order.iter().enumerate() {
+            for (resource, usage, _) in self.passes[index].all_accesses() {
+                match resource {
+                    Resource::Image(ImageId(id)) => {
+                        let image = &self.images[id];
+                        if image.imported.is_none() && self.first_use[id] == Some(position) {
+                            match self.aliases[id] {
+                                Some(previous) => {
+                                    tracker.alias_image(image.image,
+                                                     1,
+                                                     1,
+                                                     self.images[previous].image)
+                                        .unwrap()
+                                }
+                                None => {
+                                    tracker.register_image(image.image,
+                                                           1,
+                                                           1,
+                                                           vk::ImageLayout::Undefined)
+                                }
+                            }
+                        }
+                        tracker.transition_image(image.image, &image.range(), usage).unwrap();
+                    }
+                    Resource::Buffer(BufferId(id)) => {
+                        tracker.transition_buffer(self.buffers[id].buffer,
+                                               0,
+                                               vk::VK_WHOLE_SIZE,
+                                               usage)
+                            .unwrap();
+                    }
+                }
+            }
+            tracker.flush(device, command_buffer);
+            match self.targets[index] {
+                Some(ref target) => {
+                    let begin_info = vk::RenderPassBeginInfo {
+                        s_type: vk::StructureType::RenderPassBeginInfo,
+                        p_next: ptr::null(),
+                        render_pass: target.render_pass,
+                        framebuffer: target.framebuffer,
+                        render_area: vk::Rect2D {
+                            offset: vk::Offset2D { x: 0, y: 0 },
+                            extent: target.extent.clone(),
+                        },
+                        clear_value_count: target.clear_values.len() as vk::uint32_t,
+                        p_clear_values: target.clear_values.as_ptr(),
+                    };
+                    device.cmd_begin_render_pass(command_buffer,
+                                                 &begin_info,
+                                                 vk::SubpassContents::Inline);
+                    record(PassId(index), command_buffer);
+                    device.cmd_end_render_pass(command_buffer);
+                }
+                None => record(PassId(index), command_buffer),
+            }
+        }
+        for image in &self.images {
+            if let Some(usage) = image.imported.as_ref().and_then(|imported| imported.final_usage) {
+                tracker.transition_image(image.image, &image.range(), usage).unwrap();
+            }
+        }
+        for buffer in &self.buffers {
+            if let Some(usage) = buffer.final_usage {
+                tracker.transition_buffer(buffer.buffer, 0, vk::VK_WHOLE_SIZE, usage).unwrap();
+            }
+        }
+        tracker.flush(device, command_buffer);
+    }
+
+    /// Destroys the objects created by `compile`, imported resources are left alone.
+    pub fn destroy(&mut self, device: &Device) {
+        for target in self.targets.drain(..).flatten() {
+            device.destroy_framebuffer(target.framebuffer);
+            device.destroy_render_pass(target.render_pass);
+        }
+        for image in self.images.drain(..).filter(|image| image.imported.is_none()) {
+            if image.view != vk::ImageView::null() {
+                device.destroy_image_view(image.view);
+            }
+            if image.image != vk::Image::null() {
+                device.destroy_image(image.image);
+            }
+        }
+        for memory in self.memories.drain(..) {
+            device.free_memory(memory);
+        }
+    }
+
+    fn create_objects(&mut self,
+                      device: &Device,
+                      memory_properties: &vk::PhysicalDeviceMemoryProperties,
+                      nodes: Vec<ImageNode>,
+                      usage_flags: Vec<vk::ImageUsageFlags>)
+                      -> Result<(), GraphError> {
+        let mut requirements = Vec::with_capacity(nodes.len());
+        for (index, node) in nodes.into_iter().enumerate() {
+            let (image, view) = match node.imported {
+                Some(ref imported) => (imported.image, imported.view),
+                None if self.first_use[index].is_some() => {
+                    let image = device.create_image(&image_create_info(node.format,
+                                                                       &node.extent,
+                                                                       usage_flags[index]))?;
+                    requirements.push((index, device.get_image_memory_requirements(image)));
+                    (image, vk::ImageView::null())
+                }
+                None => (vk::Image::null(), vk::ImageView::null()),
+            };
+            self.images.push(GraphImage {
+                image,
+                view,
+                format: node.format,
+                extent: node.extent,
+                imported: node.imported,
+            });
+        }
+
+        let slots = alias_memory(requirements,
+                                 &self.first_use,
+                                 &self.last_use,
+                                 &mut self.aliases);
+        for slot in slots {
+            let memory_type = memory_properties.find_memory_type(slot.memory_type_bits,
+                                  vk::MemoryPropertyFlags::empty(),
+                                  vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT)
+                .ok_or(GraphError::NoCompatibleMemoryType)?;
+            let allocate_info = vk::MemoryAllocateInfo {
+                s_type: vk::StructureType::MemoryAllocateInfo,
+                p_next: ptr::null(),
+                allocation_size: slot.size,
+                memory_type_index: memory_type.index,
+            };
+            let memory = device.allocate_memory(&allocate_info)?;
+            self.memories.push(memory);
+            for index in slot.images {
+                device.bind_image_memory(self.images[index].image, memory, 0)?;
+                let view_info = image_view_create_info(self.images[index].image,
+                                                       self.images[index].format,
+                                                       self.images[index].range());
+                self.images[index].view = device.create_image_view(&view_info)?;
+            }
+        }
+
+        for position in 0..self.order.len() {
+            let index = self.order[position];
+            let target = if self.passes[index].has_attachments() {
+                Some(self.create_target(device, index, position)?)
+            } else {
+                None
+            };
+            while self.targets.len() <= index {
+                self.targets.push(None);
+            }
+            self.targets[index] = target;
+        }
+        Ok(())
+    }
+
+    fn create_target(&self,
+                     device: &Device,
+                     index: usize,
+                     position: usize)
+                     -> Result<PassTarget, GraphError> {
+        let pass = &self.passes[index];
+        let mut attachments = Vec::new();
+        let mut views = Vec::new();
+        let mut clear_values = Vec::new();
+        let mut color_references = Vec::new();
+        for &(ImageId(id), clear) in &pass.color_attachments {
+            let layout = vk::ImageLayout::ColorAttachmentOptimal;
+            color_references.push(vk::AttachmentReference {
+                attachment: attachments.len() as vk::uint32_t,
+                layout,
+            });
+            attachments.push(vk::AttachmentDescription {
+                flags: vk::AttachmentDescriptionFlags::empty(),
+                format: self.images[id].format,
+                samples: vk::SAMPLE_COUNT_1_BIT,
+                load_op: self.load_op(id, position, clear.is_some()),
+                store_op: self.store_op(id, position),
+                stencil_load_op: vk::AttachmentLoadOp::DontCare,
+                stencil_store_op: vk::AttachmentStoreOp::DontCare,
+                initial_layout: layout,
+                final_layout: layout,
+            });
+            views.push(self.images[id].view);
+            let color = clear.unwrap_or([0.0; 4]);
+            clear_values.push(vk::ClearValue::new_color(vk::ClearColorValue::new_float32(color)));
+        }
+        let depth_reference = pass.depth_attachment.map(|(ImageId(id), clear)| {
+            let layout = vk::ImageLayout::DepthStencilAttachmentOptimal;
+            let load_op = self.load_op(id, position, clear.is_some());
+            let store_op = self.store_op(id, position);
+            let (stencil_load_op, stencil_store_op) = if has_stencil(self.images[id].format) {
+                (load_op, store_op)
+            } else {
+                (vk::AttachmentLoadOp::DontCare, vk::AttachmentStoreOp::DontCare)
+            };
+            let reference = vk::AttachmentReference {
+                attachment: attachments.len() as vk::uint32_t,
+                layout,
+            };
+            attachments.push(vk::AttachmentDescription {
+                flags: vk::AttachmentDescriptionFlags::empty(),
+                format: self.images[id].format,
+                samples: vk::SAMPLE_COUNT_1_BIT,
+                load_op,
+                store_op,
+                stencil_load_op,
+                stencil_store_op,
+                initial_layout: layout,
+                final_layout: layout,
+            });
+            views.push(self.images[id].view);
+            let (depth, stencil) = clear.unwrap_or((1.0, 0));
+            clear_values.push(vk::ClearValue::new_depth_stencil(vk::ClearDepthStencilValue {
+                depth,
+                stencil,
+            }));
+            reference
+        });
+
+        let extent = {
+            let mut ids = pass.color_attachments
+                .iter()
+                .map(|&(ImageId(id), _)| id)
+                .chain(pass.depth_attachment.map(|(ImageId(id), _)| id));
+            let extent = self.images[ids.next().unwrap()].extent.clone();
+            if ids.any(|id| {
+                self.images[id].extent.width != extent.width ||
+                self.images[id].extent.height != extent.height
+            }) {
+                return Err(GraphError::ExtentMismatch(PassId(index)));
+            }
+            extent
+        };
+
+        let subpass = vk::SubpassDescription {
+            flags: vk::SubpassDescriptionFlags::empty(),
+            pipeline_bind_point: vk::PipelineBindPoint::Graphics,
+            input_attachment_count: 0,
+            p_input_attachments: ptr::null(),
+            color_attachment_count: color_references.len() as vk::uint32_t,
+            p_color_attachments: color_references.as_ptr(),
+            p_resolve_attachments: ptr::null(),
+            p_depth_stencil_attachment: depth_reference.as_ref()
+                .map_or(ptr::null(), |reference| reference),
+            preserve_attachment_count: 0,
+            p_preserve_attachments: ptr::null(),
+        };
+        // The barriers recorded by `execute` take care of the dependencies outside of the pass.
+        let render_pass_info = vk::RenderPassCreateInfo {
+            s_type: vk::StructureType::RenderPassCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            attachment_count: attachments.len() as vk::uint32_t,
+            p_attachments: attachments.as_ptr(),
+            subpass_count: 1,
+            p_subpasses: &subpass,
+            dependency_count: 0,
+            p_dependencies: ptr::null(),
+        };
+        let render_pass = device.create_render_pass(&render_pass_info)?;
+        let framebuffer_info = vk::FramebufferCreateInfo {
+            s_type: vk::StructureType::FramebufferCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            render_pass,
+            attachment_count: views.len() as vk::uint32_t,
+            p_attachments: views.as_ptr(),
+            width: extent.width,
+            height: extent.height,
+            layers: 1,
+        };
+        let framebuffer = match device.create_framebuffer(&framebuffer_info) {
+            Ok(framebuffer) => framebuffer,
+            Err(err) => {
+                device.destroy_render_pass(render_pass);
+                return Err(GraphError::VkError(err));
+            }
+        };
+        Ok(PassTarget {
+            render_pass,
+            framebuffer,
+            extent,
+            clear_values,
+        })
+    }
+
+    fn load_op(&self, image: usize, position: usize, clear: bool) -> vk::AttachmentLoadOp {
+        let defined = match self.images[image].imported {
+            Some(ref imported) => imported.initial_layout != vk::ImageLayout::Undefined,
+            None => false,
+        };
+        if clear {
+            vk::AttachmentLoadOp::Clear
+        } else if defined || self.first_use[image].is_some_and(|first| first < position) {
+            vk::AttachmentLoadOp::Load
+        } else {
+            vk::AttachmentLoadOp::DontCare
+        }
+    }
+
+    fn store_op(&self, image: usize, position: usize) -> vk::AttachmentStoreOp {
+        if self.images[image].imported.is_some() ||
+           self.last_use[image].is_some_and(|last| last > position) {
+            vk::AttachmentStoreOp::Store
+        } else {
+            vk::AttachmentStoreOp::DontCare
+        }
+    }
+}
+
+impl GraphImage {
+    fn range(&self) -> vk::ImageSubresourceRange {
+        let (level_count, layer_count) = self.imported
+            .as_ref()
+            .map_or((1, 1), |imported| (imported.mip_levels, imported.array_layers));
+        vk::ImageSubresourceRange {
+            aspect_mask: aspect_mask(self.format),
+            base_mip_level: 0,
+            level_count,
+            base_array_layer: 0,
+            layer_count,
+        }
+    }
+}
+
+/// Greedily puts every transient image into the first allocation whose images are no longer
+/// used when it is first used, and records in `aliases` the image whose memory each one takes
+/// over.
+fn alias_memory(mut requirements: Vec<(usize, vk::MemoryRequirements)>,
+                first_use: &[Option<usize>],
+                last_use: &[Option<usize>],
+                aliases: &mut [Option<usize>])
+                -> Vec<MemorySlot> {
+    requirements.sort_by_key(|&(index, _)| first_use[index]);
+    let mut slots: Vec<MemorySlot> = Vec::new();
+    for (index, requirement) in requirements {
+        let first_use = first_use[index].unwrap();
+        let last_use = last_use[index].unwrap();
+        let free_slot = slots.iter().position(|slot| {
+            slot.last_use < first_use && slot.memory_type_bits & requirement.memory_type_bits != 0
+        });
+        match free_slot {
+            Some(slot_index) => {
+                let slot = &mut slots[slot_index];
+                aliases[index] = slot.images.last().cloned();
+                slot.images.push(index);
+                slot.size = cmp::max(slot.size, requirement.size);
+                slot.memory_type_bits &= requirement.memory_type_bits;
+                slot.last_use = last_use;
+            }
+            None => {
+                slots.push(MemorySlot {
+                    images: vec![index],
+                    size: requirement.size,
+                    memory_type_bits: requirement.memory_type_bits,
+                    last_use,
+                })
+            }
+        }
+    }
+    slots
+}
+
+fn image_usage_flags(usage: Usage) -> vk::ImageUsageFlags {
+    match usage {
+        Usage::TransferSrc => vk::IMAGE_USAGE_TRANSFER_SRC_BIT,
+        Usage::TransferDst => vk::IMAGE_USAGE_TRANSFER_DST_BIT,
+        Usage::Sampled(_) => vk::IMAGE_USAGE_SAMPLED_BIT,
+        Usage::StorageRead(_) |
+        Usage::StorageWrite(_) |
+        Usage::StorageReadWrite(_) => vk::IMAGE_USAGE_STORAGE_BIT,
+        Usage::ColorAttachment => vk::IMAGE_USAGE_COLOR_ATTACHMENT_BIT,
+        Usage::DepthStencilAttachment |
+        Usage::DepthStencilReadOnly => vk::IMAGE_USAGE_DEPTH_STENCIL_ATTACHMENT_BIT,
+        Usage::InputAttachment => vk::IMAGE_USAGE_INPUT_ATTACHMENT_BIT,
+        _ => vk::ImageUsageFlags::empty(),
+    }
+}
+
+fn has_depth(format: vk::Format) -> bool {
+    matches!(format,
+             vk::Format::D16Unorm |
+             vk::Format::X8D24UnormPack32 |
+             vk::Format::D32Sfloat |
+             vk::Format::D16UnormS8Uint |
+             vk::Format::D24UnormS8Uint |
+             vk::Format::D32SfloatS8Uint)
+}
+
+fn has_stencil(format: vk::Format) -> bool {
+    matches!(format,
+             vk::Format::S8Uint |
+             vk::Format::D16UnormS8Uint |
+             vk::Format::D24UnormS8Uint |
+             vk::Format::D32SfloatS8Uint)
+}
+
+fn aspect_mask(format: vk::Format) -> vk::ImageAspectFlags {
+    let mut aspect_mask = vk::ImageAspectFlags::empty();
+    if has_depth(format) {
+        aspect_mask |= vk::IMAGE_ASPECT_DEPTH_BIT;
+    }
+    if has_stencil(format) {
+        aspect_mask |= vk::IMAGE_ASPECT_STENCIL_BIT;
+    }
+    if aspect_mask.is_empty() {
+        vk::IMAGE_ASPECT_COLOR_BIT
+    } else {
+        aspect_mask
+    }
+}
+
+fn image_create_info(format: vk::Format,
+                     extent: &vk::Extent2D,
+                     usage: vk::ImageUsageFlags)
+                     -> vk::ImageCreateInfo {
+    vk::ImageCreateInfo {
+        s_type: vk::StructureType::ImageCreateInfo,
+        p_next: ptr::null(),
+        flags: vk::ImageCreateFlags::empty(),
+        image_type: vk::ImageType::Type2d,
+        format,
+        extent: vk::Extent3D {
+            width: extent.width,
+            height: extent.height,
+            depth: 1,
+        },
+        mip_levels: 1,
+        array_layers: 1,
+        samples: vk::SAMPLE_COUNT_1_BIT,
+        tiling: vk::ImageTiling::Optimal,
+        usage,
+        sharing_mode: vk::SharingMode::Exclusive,
+        queue_family_index_count: 0,
+        p_queue_family_indices: ptr::null(),
+        initial_layout: vk::ImageLayout::Undefined,
+    }
+}
+
+fn image_view_create_info(image: vk::Image,
+                          format: vk::Format,
+                          range: vk::ImageSubresourceRange)
+                          -> vk::ImageViewCreateInfo {
+    vk::ImageViewCreateInfo {
+        s_type: vk::StructureType::ImageViewCreateInfo,
+        p_next: ptr::null(),
+        flags: Default::default(),
+        view_type: vk::ImageViewType::Type2d,
+        format,
+        components: vk::ComponentMapping {
+            r: vk::ComponentSwizzle::Identity,
+            g: vk::ComponentSwizzle::Identity,
+            b: vk::ComponentSwizzle::Identity,
+            a: vk::ComponentSwizzle::Identity,
+        },
+        subresource_range: range,
+        image,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vk;
+    use barrier::Usage;
+    use super::{alias_memory, RenderGraph};
+
+    fn extent() -> vk::Extent2D {
+        vk::Extent2D {
+            width: 64,
+            height: 64,
+        }
+    }
+
+    fn requirements(size: vk::DeviceSize) -> vk::MemoryRequirements {
+        vk::MemoryRequirements {
+            size,
+            alignment: 256,
+            memory_type_bits: 0b11,
+        }
+    }
+
+    #[test]
+    fn reads_run_after_the_last_earlier_write() {
+        let mut graph = RenderGraph::new();
+        let image = graph.create_image(vk::Format::R8g8b8a8Unorm, extent());
+        let other = graph.create_image(vk::Format::R8g8b8a8Unorm, extent());
+        graph.add_pass("read other").read_image(other, Usage::TransferSrc);
+        graph.add_pass("write").write_image(image, Usage::TransferDst);
+        graph.add_pass("write other").write_image(other, Usage::TransferDst);
+        graph.add_pass("read").read_image(image, Usage::TransferSrc);
+        assert_eq!(graph.schedule(), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn writes_run_after_the_earlier_reads() {
+        // Reading last frame's contents before overwriting them.
+        let mut graph = RenderGraph::new();
+        let history = graph.create_image(vk::Format::R8g8b8a8Unorm, extent());
+        graph.add_pass("read history")
+            .read_image(history, Usage::Sampled(vk::PIPELINE_STAGE_FRAGMENT_SHADER_BIT));
+        graph.add_pass("write history").color_attachment(history, None);
+        assert_eq!(graph.schedule(), vec![0, 1]);
+    }
+
+    #[test]
+    fn reads_between_writes_see_the_write_before_them() {
+        let mut graph = RenderGraph::new();
+        let image = graph.create_image(vk::Format::R8g8b8a8Unorm, extent());
+        graph.add_pass("write 1").write_image(image, Usage::TransferDst);
+        graph.add_pass("read 1").read_image(image, Usage::TransferSrc);
+        graph.add_pass("write 2").write_image(image, Usage::TransferDst);
+        graph.add_pass("read 2").read_image(image, Usage::TransferSrc);
+        assert_eq!(graph.schedule(), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn writes_keep_their_order() {
+        let mut graph = RenderGraph::new();
+        let buffer = graph.import_buffer(vk::Buffer::null(), 256, None);
+        graph.add_pass("write 1").write_buffer(buffer, Usage::TransferDst);
+        graph.add_pass("write 2").write_buffer(buffer, Usage::TransferDst);
+        graph.add_pass("write 3").write_buffer(buffer, Usage::TransferDst);
+        assert_eq!(graph.schedule(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn unused_passes_keep_their_place() {
+        let mut graph = RenderGraph::new();
+        let image = graph.create_image(vk::Format::R8g8b8a8Unorm, extent());
+        graph.add_pass("unused");
+        graph.add_pass("write").color_attachment(image, None);
+        graph.add_pass("read").read_image(image, Usage::TransferSrc);
+        assert_eq!(graph.schedule(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn disjoint_lifetimes_share_memory() {
+        let first_use = [Some(0), Some(2)];
+        let last_use = [Some(1), Some(3)];
+        let mut aliases = [None, None];
+        let slots = alias_memory(vec![(0, requirements(1024)), (1, requirements(4096))],
+                                 &first_use,
+                                 &last_use,
+                                 &mut aliases);
+        assert_eq!(slots.len(), 1);
+        assert_eq!(slots[0].images, vec![0, 1]);
+        assert_eq!(slots[0].size, 4096);
+        assert_eq!(aliases, [None, Some(0)]);
+    }
+
+    #[test]
+    fn overlapping_lifetimes_dont_share_memory() {
+        let first_use = [Some(0), Some(1)];
+        let last_use = [Some(1), Some(2)];
+        let mut aliases = [None, None];
+        let slots = alias_memory(vec![(0, requirements(1024)), (1, requirements(1024))],
+                                 &first_use,
+                                 &last_use,
+                                 &mut aliases);
+        assert_eq!(slots.len(), 2);
+        assert_eq!(aliases, [None, None]);
+    }
+}
@@ -4,8 +4,10 @@ use std::ptr;
 use std::mem;
 use std::os::raw::*;
 use std::path::Path;
+use std::sync::Arc;
 use vk;
 use device::Device;
+use allocator::{self, AllocationCallbacks, HostAllocator};
 use shared_library::dynamic_library::DynamicLibrary;
 
 #[derive(Debug)]
@@ -18,45 +20,83 @@ pub enum DeviceError {
 pub struct Instance<'r> {
     handle: vk::Instance,
     instance_fn: vk::InstanceFn,
+    allocation_callbacks: Option<Arc<AllocationCallbacks>>,
     _lifetime: ::std::marker::PhantomData<&'r ()>,
 }
 
 impl<'r> Instance<'r> {
-    pub unsafe fn from_raw(handle: vk::Instance, instance_fn: vk::InstanceFn) -> Self {
+    pub unsafe fn from_raw(handle: vk::Instance,
+                           instance_fn: vk::InstanceFn,
+                           allocation_callbacks: Option<Arc<AllocationCallbacks>>)
+                           -> Self {
         Instance {
-            handle: handle,
-            instance_fn: instance_fn,
+            handle,
+            instance_fn,
+            allocation_callbacks,
             _lifetime: ::std::marker::PhantomData,
         }
     }
 
+    /// The `p_allocator` passed to every instance level create and destroy function.
+    pub fn allocation_callbacks(&self) -> *const vk::AllocationCallbacks {
+        allocator::as_ptr(&self.allocation_callbacks)
+    }
+
+    /// Creates a device that uses the same host allocator as this instance.
     pub fn create_device(&self,
                          physical_device: vk::PhysicalDevice,
                          create_info: &vk::DeviceCreateInfo)
-                         -> Result<Device, DeviceError> {
+                         -> Result<Device<'_>, DeviceError> {
+        self.create_device_with_callbacks(physical_device,
+                                          create_info,
+                                          self.allocation_callbacks.clone())
+    }
+
+    /// Creates a device that allocates host memory through `allocator`.
+    pub fn create_device_with_allocator(&self,
+                                        physical_device: vk::PhysicalDevice,
+                                        create_info: &vk::DeviceCreateInfo,
+                                        allocator: Arc<dyn HostAllocator>)
+                                        -> Result<Device<'_>, DeviceError> {
+        self.create_device_with_callbacks(physical_device,
+                                          create_info,
+                                          Some(Arc::new(AllocationCallbacks::new(allocator))))
+    }
+
+    fn create_device_with_callbacks(&self,
+                                    physical_device: vk::PhysicalDevice,
+                                    create_info: &vk::DeviceCreateInfo,
+                                    allocation_callbacks: Option<Arc<AllocationCallbacks>>)
+                                    -> Result<Device<'_>, DeviceError> {
         unsafe {
             let mut device = mem::uninitialized();
             let err_code = self.instance_fn
-                .create_device(physical_device, create_info, ptr::null(), &mut device);
+                .create_device(physical_device,
+                               create_info,
+                               allocator::as_ptr(&allocation_callbacks),
+                               &mut device);
             if err_code != vk::Result::Success {
                 return Err(DeviceError::VkError(err_code));
             }
             let device_fn = vk::DeviceFn::load(|name| unsafe {
                     mem::transmute(self.instance_fn.get_device_proc_addr(device, name.as_ptr()))
                 }).map_err(|err| DeviceError::LoadError(err))?;
-            Ok(Device::from_raw(device, device_fn))
+            let limits = self.get_physical_device_properties(physical_device).limits;
+            Ok(Device::from_raw(device, device_fn, limits, allocation_callbacks))
         }
     }
 
     pub fn destroy_instance(&self) {
         unsafe {
-            self.instance_fn.destroy_instance(self.handle, ptr::null());
+            self.instance_fn.destroy_instance(self.handle, self.allocation_callbacks());
         }
     }
 
     pub fn destroy_debug_report_callback_ext(&self, debug: vk::DebugReportCallbackEXT) {
         unsafe {
-            self.instance_fn.destroy_debug_report_callback_ext(self.handle, debug, ptr::null());
+            self.instance_fn.destroy_debug_report_callback_ext(self.handle,
+                                                               debug,
+                                                               self.allocation_callbacks());
         }
     }
 
@@ -68,7 +108,7 @@ impl<'r> Instance<'r> {
             let err_code = self.instance_fn
                 .create_debug_report_callback_ext(self.handle,
                                                   create_info,
-                                                  ptr::null(),
+                                                  self.allocation_callbacks(),
                                                   &mut debug_cb);
             match err_code {
                 vk::Result::Success => Ok(debug_cb),
@@ -77,6 +117,17 @@ impl<'r> Instance<'r> {
         }
     }
 
+    pub fn get_physical_device_properties(&self,
+                                          physical_device: vk::PhysicalDevice)
+                                          -> vk::PhysicalDeviceProperties {
+        unsafe {
+            let mut prop = mem::zeroed();
+            self.instance_fn
+                .get_physical_device_properties(physical_device, &mut prop);
+            prop
+        }
+    }
+
     pub fn get_physical_device_memory_properties(&self,
                                                  physical_device: vk::PhysicalDevice)
                                                  -> vk::PhysicalDeviceMemoryProperties {
@@ -155,7 +206,7 @@ impl<'r> Instance<'r> {
 
     pub fn destroy_surface_khr(&self, surface: vk::SurfaceKHR) {
         unsafe {
-            self.instance_fn.destroy_surface_khr(self.handle, surface, ptr::null());
+            self.instance_fn.destroy_surface_khr(self.handle, surface, self.allocation_callbacks());
         }
     }
 
@@ -165,7 +216,10 @@ impl<'r> Instance<'r> {
         unsafe {
             let mut surface = mem::uninitialized();
             let err_code = self.instance_fn
-                .create_xlib_surface_khr(self.handle, create_info, ptr::null(), &mut surface);
+                .create_xlib_surface_khr(self.handle,
+                                         create_info,
+                                         self.allocation_callbacks(),
+                                         &mut surface);
             match err_code {
                 vk::Result::Success => Ok(surface),
                 _ => Err(err_code),
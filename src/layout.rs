@@ -0,0 +1,127 @@
+use prelude::*;
+use std::collections::HashMap;
+use std::ptr;
+use std::slice;
+use vk;
+use device::Device;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct BindingKey {
+    binding: vk::uint32_t,
+    descriptor_type: vk::DescriptorType,
+    descriptor_count: vk::uint32_t,
+    stage_flags: vk::ShaderStageFlags,
+    immutable_samplers: Vec<vk::Sampler>,
+}
+
+impl BindingKey {
+    fn new(binding: &vk::DescriptorSetLayoutBinding) -> BindingKey {
+        // Immutable samplers are ignored for every other descriptor type.
+        let uses_samplers = matches!(binding.descriptor_type,
+                                     vk::DescriptorType::Sampler |
+                                     vk::DescriptorType::CombinedImageSampler);
+        let immutable_samplers = if uses_samplers && !binding.p_immutable_samplers.is_null() {
+            unsafe {
+                slice::from_raw_parts(binding.p_immutable_samplers,
+                                      binding.descriptor_count as usize)
+                    .to_vec()
+            }
+        } else {
+            Vec::new()
+        };
+        BindingKey {
+            binding: binding.binding,
+            descriptor_type: binding.descriptor_type,
+            descriptor_count: binding.descriptor_count,
+            stage_flags: binding.stage_flags,
+            immutable_samplers,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct PipelineLayoutKey {
+    set_layouts: Vec<vk::DescriptorSetLayout>,
+    // Stage flags, offset and size of every push constant range.
+    push_constant_ranges: Vec<(vk::ShaderStageFlags, vk::uint32_t, vk::uint32_t)>,
+}
+
+/// Creates every distinct `DescriptorSetLayout` and `PipelineLayout` only once.
+///
+/// Set layouts are looked up by the contents of their bindings, including immutable samplers,
+/// regardless of the order of the bindings. Pipeline layouts are looked up by their set
+/// layouts and push constant ranges, so they deduplicate best when the set layouts come from
+/// the same cache. Everything is destroyed when the cache is dropped.
+pub struct LayoutCache<'a> {
+    device: &'a Device<'a>,
+    descriptor_set_layouts: HashMap<Vec<BindingKey>, vk::DescriptorSetLayout>,
+    pipeline_layouts: HashMap<PipelineLayoutKey, vk::PipelineLayout>,
+}
+
+impl<'a> LayoutCache<'a> {
+    pub fn new(device: &'a Device<'a>) -> LayoutCache<'a> {
+        LayoutCache {
+            device,
+            descriptor_set_layouts: HashMap::new(),
+            pipeline_layouts: HashMap::new(),
+        }
+    }
+
+    pub fn descriptor_set_layout(&mut self,
+                                 bindings: &[vk::DescriptorSetLayoutBinding])
+                                 -> VkResult<vk::DescriptorSetLayout> {
+        let mut key: Vec<BindingKey> = bindings.iter().map(BindingKey::new).collect();
+        key.sort_by_key(|binding| binding.binding);
+        if let Some(&layout) = self.descriptor_set_layouts.get(&key) {
+            return Ok(layout);
+        }
+        let create_info = vk::DescriptorSetLayoutCreateInfo {
+            s_type: vk::StructureType::DescriptorSetLayoutCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            binding_count: bindings.len() as vk::uint32_t,
+            p_bindings: bindings.as_ptr(),
+        };
+        let layout = self.device.create_descriptor_set_layout(&create_info)?;
+        self.descriptor_set_layouts.insert(key, layout);
+        Ok(layout)
+    }
+
+    pub fn pipeline_layout(&mut self,
+                           set_layouts: &[vk::DescriptorSetLayout],
+                           push_constant_ranges: &[vk::PushConstantRange])
+                           -> VkResult<vk::PipelineLayout> {
+        let key = PipelineLayoutKey {
+            set_layouts: set_layouts.to_vec(),
+            push_constant_ranges: push_constant_ranges.iter()
+                .map(|range| (range.stage_flags, range.offset, range.size))
+                .collect(),
+        };
+        if let Some(&layout) = self.pipeline_layouts.get(&key) {
+            return Ok(layout);
+        }
+        let create_info = vk::PipelineLayoutCreateInfo {
+            s_type: vk::StructureType::PipelineLayoutCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            set_layout_count: set_layouts.len() as vk::uint32_t,
+            p_set_layouts: set_layouts.as_ptr(),
+            push_constant_range_count: push_constant_ranges.len() as vk::uint32_t,
+            p_push_constant_ranges: push_constant_ranges.as_ptr(),
+        };
+        let layout = self.device.create_pipeline_layout(&create_info)?;
+        self.pipeline_layouts.insert(key, layout);
+        Ok(layout)
+    }
+}
+
+impl<'a> Drop for LayoutCache<'a> {
+    fn drop(&mut self) {
+        for (_, layout) in self.pipeline_layouts.drain() {
+            self.device.destroy_pipeline_layout(layout);
+        }
+        for (_, layout) in self.descriptor_set_layouts.drain() {
+            self.device.destroy_descriptor_set_layout(layout);
+        }
+    }
+}
@@ -1,8 +1,26 @@
 #[macro_use]
 extern crate shared_library;
 
+pub mod alloc;
+pub mod allocator;
+pub mod barrier;
+pub mod command;
+pub mod deletion;
+pub mod descriptor;
 pub mod instance;
 pub mod device;
 pub mod entry;
+pub mod frame;
+pub mod graph;
+pub mod layout;
+pub mod memory;
+pub mod pipeline;
+pub mod sparse;
+pub mod surface;
+pub mod swapchain;
+pub mod ring;
+pub mod upload;
+pub mod util;
+pub mod render_pass;
 pub mod prelude;
 pub mod vk;
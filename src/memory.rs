@@ -0,0 +1,463 @@
+use prelude::*;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::slice;
+use vk;
+use device::Device;
+
+#[derive(Debug)]
+pub enum MapError {
+    VkError(vk::Result),
+    /// The mapped pointer is not aligned to `mem::align_of::<T>()`.
+    Misaligned { address: usize, align: usize },
+    /// The mapped size is not a multiple of `mem::size_of::<T>()`.
+    SizeMismatch {
+        size: vk::DeviceSize,
+        element_size: vk::DeviceSize,
+    },
+    /// `VK_WHOLE_SIZE` was requested for memory whose size is unknown because it was not
+    /// allocated through this device while memory tracking was enabled.
+    UnknownAllocation(vk::DeviceMemory),
+    /// The mapped range does not lie within the allocation.
+    OutOfRange {
+        offset: vk::DeviceSize,
+        size: vk::DeviceSize,
+        allocation_size: vk::DeviceSize,
+    },
+}
+
+/// A mapped range of device memory, viewed as a slice of `T`. The memory is unmapped when
+/// this is dropped.
+///
+/// Writes to memory that is not `MEMORY_PROPERTY_HOST_COHERENT_BIT` are only visible to the
+/// device after `flush`, and device writes are only visible to the host after `invalidate`.
+pub struct MappedMemory<'a, T: 'a> {
+    device: &'a Device<'a>,
+    memory: vk::DeviceMemory,
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+    reaches_end: bool,
+    data: *mut T,
+    len: usize,
+}
+
+impl<'a, T> MappedMemory<'a, T> {
+    /// # Safety
+    ///
+    /// `data` has to point to `len` elements of memory mapped from `memory` at `offset`, and
+    /// `reaches_end` has to be true if the mapping extends to the end of the allocation.
+    pub unsafe fn from_raw(device: &'a Device<'a>,
+                           memory: vk::DeviceMemory,
+                           offset: vk::DeviceSize,
+                           size: vk::DeviceSize,
+                           reaches_end: bool,
+                           data: *mut T,
+                           len: usize)
+                           -> Self {
+        MappedMemory {
+            device,
+            memory,
+            offset,
+            size,
+            reaches_end,
+            data,
+            len,
+        }
+    }
+
+    pub fn memory(&self) -> vk::DeviceMemory {
+        self.memory
+    }
+
+    pub fn offset(&self) -> vk::DeviceSize {
+        self.offset
+    }
+
+    pub fn size(&self) -> vk::DeviceSize {
+        self.size
+    }
+
+    /// Makes host writes to the mapped range visible to the device.
+    pub fn flush(&self) -> VkResult<()> {
+        self.device.flush_mapped_memory_ranges(&[self.atom_aligned_range()])
+    }
+
+    /// Makes device writes to the mapped range visible to the host.
+    pub fn invalidate(&self) -> VkResult<()> {
+        self.device.invalidate_mapped_memory_ranges(&[self.atom_aligned_range()])
+    }
+
+    /// The mapped range widened to multiples of `non_coherent_atom_size`, as required by
+    /// `vkFlushMappedMemoryRanges`. A range that reaches the end of the allocation uses
+    /// `VK_WHOLE_SIZE` instead, because rounding up could step past the allocation.
+    fn atom_aligned_range(&self) -> vk::MappedMemoryRange {
+        let atom_size = ::std::cmp::max(self.device.limits().non_coherent_atom_size, 1);
+        let offset = self.offset - self.offset % atom_size;
+        let end = self.offset + self.size;
+        let aligned_end = end.div_ceil(atom_size) * atom_size;
+        let past_allocation = self.device
+            .allocation_size(self.memory)
+            .is_some_and(|allocation_size| aligned_end >= allocation_size);
+        let reaches_end = self.reaches_end || past_allocation;
+        vk::MappedMemoryRange {
+            s_type: vk::StructureType::MappedMemoryRange,
+            p_next: ptr::null(),
+            memory: self.memory,
+            offset,
+            size: if reaches_end {
+                vk::VK_WHOLE_SIZE
+            } else {
+                aligned_end - offset
+            },
+        }
+    }
+}
+
+impl<'a, T> Deref for MappedMemory<'a, T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.data, self.len) }
+    }
+}
+
+impl<'a, T> DerefMut for MappedMemory<'a, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.data, self.len) }
+    }
+}
+
+impl<'a, T> Drop for MappedMemory<'a, T> {
+    fn drop(&mut self) {
+        self.device.unmap_memory(self.memory);
+    }
+}
+
+/// Common ways memory is used, for picking a memory type with
+/// `PhysicalDeviceMemoryProperties::find_memory_type_for_usage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryUsage {
+    /// Only accessed by the device, e.g. render targets and static vertex data.
+    GpuOnly,
+    /// Written by the host and read by the device, e.g. staging buffers and uniforms.
+    CpuToGpu,
+    /// Written by the device and read back by the host.
+    GpuToCpu,
+}
+
+impl MemoryUsage {
+    /// Returns the `(required, preferred)` property flags for this usage.
+    pub fn property_flags(self) -> (vk::MemoryPropertyFlags, vk::MemoryPropertyFlags) {
+        match self {
+            MemoryUsage::GpuOnly => {
+                (vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT, vk::MemoryPropertyFlags::empty())
+            }
+            MemoryUsage::CpuToGpu => {
+                (vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT, vk::MEMORY_PROPERTY_HOST_COHERENT_BIT)
+            }
+            MemoryUsage::GpuToCpu => {
+                (vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT,
+                 vk::MEMORY_PROPERTY_HOST_CACHED_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT)
+            }
+        }
+    }
+
+    /// Returns the property flags that are avoided for this usage when types are otherwise
+    /// equally preferred. Host visible device local memory is often a small heap that is
+    /// better left to the resources that need it, so `CpuToGpu` avoids it.
+    pub fn avoided_flags(self) -> vk::MemoryPropertyFlags {
+        match self {
+            MemoryUsage::CpuToGpu => vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT,
+            MemoryUsage::GpuOnly | MemoryUsage::GpuToCpu => vk::MemoryPropertyFlags::empty(),
+        }
+    }
+}
+
+/// The memory type picked by `find_memory_type` together with the heap it allocates from.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryTypeInfo {
+    pub index: vk::uint32_t,
+    pub property_flags: vk::MemoryPropertyFlags,
+    pub heap_index: vk::uint32_t,
+    pub heap_size: vk::DeviceSize,
+    pub heap_flags: vk::MemoryHeapFlags,
+}
+
+impl vk::PhysicalDeviceMemoryProperties {
+    /// Finds a memory type that is allowed by `memory_type_bits` and has all of the `required`
+    /// flags. Among those, the type with the most `preferred` flags wins, so this falls back to
+    /// a type that only has the `required` flags if nothing better exists.
+    pub fn find_memory_type(&self,
+                            memory_type_bits: vk::uint32_t,
+                            required: vk::MemoryPropertyFlags,
+                            preferred: vk::MemoryPropertyFlags)
+                            -> Option<MemoryTypeInfo> {
+        self.find_memory_type_avoiding(memory_type_bits,
+                                       required,
+                                       preferred,
+                                       vk::MemoryPropertyFlags::empty())
+    }
+
+    /// Like `find_memory_type`, but among the types with the most `preferred` flags the one
+    /// with the fewest `avoided` flags wins.
+    pub fn find_memory_type_avoiding(&self,
+                                     memory_type_bits: vk::uint32_t,
+                                     required: vk::MemoryPropertyFlags,
+                                     preferred: vk::MemoryPropertyFlags,
+                                     avoided: vk::MemoryPropertyFlags)
+                                     -> Option<MemoryTypeInfo> {
+        let mut best: Option<((u32, i32), usize)> = None;
+        for (index, memory_type) in self.memory_types[..self.memory_type_count as usize]
+            .iter()
+            .enumerate() {
+            if memory_type_bits & (1 << index) == 0 ||
+               !memory_type.property_flags.subset(required) {
+                continue;
+            }
+            let score = ((memory_type.property_flags & preferred).flags().count_ones(),
+                         -((memory_type.property_flags & avoided).flags().count_ones() as i32));
+            if best.is_none_or(|(best_score, _)| score > best_score) {
+                best = Some((score, index));
+            }
+        }
+        best.map(|(_, index)| self.memory_type_info(index as vk::uint32_t))
+    }
+
+    pub fn find_memory_type_for_usage(&self,
+                                      memory_type_bits: vk::uint32_t,
+                                      usage: MemoryUsage)
+                                      -> Option<MemoryTypeInfo> {
+        let (required, preferred) = usage.property_flags();
+        self.find_memory_type_avoiding(memory_type_bits, required, preferred, usage.avoided_flags())
+    }
+
+    /// Describes the memory type at `index` and its heap.
+    pub fn memory_type_info(&self, index: vk::uint32_t) -> MemoryTypeInfo {
+        let memory_type = &self.memory_types[index as usize];
+        let heap = &self.memory_heaps[memory_type.heap_index as usize];
+        MemoryTypeInfo {
+            index,
+            property_flags: memory_type.property_flags,
+            heap_index: memory_type.heap_index,
+            heap_size: heap.size,
+            heap_flags: heap.flags,
+        }
+    }
+}
+
+/// How much memory is currently allocated from a heap.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapUsage {
+    pub heap_index: vk::uint32_t,
+    /// `MemoryHeap::size`
+    pub size: vk::DeviceSize,
+    pub allocated: vk::DeviceSize,
+    pub allocation_count: usize,
+}
+
+impl HeapUsage {
+    /// The fraction of the heap that is allocated.
+    pub fn usage(&self) -> f64 {
+        if self.size == 0 {
+            return 0.0;
+        }
+        self.allocated as f64 / self.size as f64
+    }
+}
+
+/// How much memory is currently allocated with a memory type.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryTypeUsage {
+    pub memory_type_index: vk::uint32_t,
+    pub heap_index: vk::uint32_t,
+    pub allocated: vk::DeviceSize,
+    pub allocation_count: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryStatistics {
+    pub heaps: Vec<HeapUsage>,
+    pub memory_types: Vec<MemoryTypeUsage>,
+}
+
+/// Counts the memory allocated per memory type and heap. Enabled on a `Device` with
+/// `Device::enable_memory_tracking`.
+pub struct MemoryTracker {
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    memory_types: Vec<MemoryTypeUsage>,
+    warning_fraction: f64,
+    warned: Vec<bool>,
+}
+
+impl MemoryTracker {
+    pub fn new(memory_properties: vk::PhysicalDeviceMemoryProperties) -> MemoryTracker {
+        let memory_type_count = memory_properties.memory_type_count as usize;
+        let memory_types = memory_properties.memory_types[..memory_type_count]
+            .iter()
+            .enumerate()
+            .map(|(index, memory_type)| {
+                MemoryTypeUsage {
+                    memory_type_index: index as vk::uint32_t,
+                    heap_index: memory_type.heap_index,
+                    allocated: 0,
+                    allocation_count: 0,
+                }
+            })
+            .collect();
+        let heap_count = memory_properties.memory_heap_count as usize;
+        MemoryTracker {
+            memory_properties,
+            memory_types,
+            warning_fraction: 1.0,
+            warned: vec![false; heap_count],
+        }
+    }
+
+    /// Heaps whose usage rises above `fraction` of their size are reported once by
+    /// `record_allocation`, until their usage drops below `fraction` again.
+    pub fn set_warning_fraction(&mut self, fraction: f64) {
+        self.warning_fraction = fraction;
+        for warned in self.warned.iter_mut() {
+            *warned = false;
+        }
+    }
+
+    /// Returns the usage of the heap if this allocation crossed the warning fraction.
+    pub fn record_allocation(&mut self,
+                             memory_type_index: vk::uint32_t,
+                             size: vk::DeviceSize)
+                             -> Option<HeapUsage> {
+        let heap_index = {
+            let usage = &mut self.memory_types[memory_type_index as usize];
+            usage.allocated += size;
+            usage.allocation_count += 1;
+            usage.heap_index
+        };
+        let heap = self.heap_usage(heap_index);
+        if !self.warned[heap_index as usize] && heap.usage() >= self.warning_fraction {
+            self.warned[heap_index as usize] = true;
+            return Some(heap);
+        }
+        None
+    }
+
+    /// Every free has to match an earlier `record_allocation` of the same size and type.
+    pub fn record_free(&mut self, memory_type_index: vk::uint32_t, size: vk::DeviceSize) {
+        let heap_index = {
+            let usage = &mut self.memory_types[memory_type_index as usize];
+            let allocated = usage.allocated.checked_sub(size);
+            let allocation_count = usage.allocation_count.checked_sub(1);
+            debug_assert!(allocated.is_some() && allocation_count.is_some(),
+                          "freed more memory than was allocated from memory type {}",
+                          memory_type_index);
+            usage.allocated = allocated.unwrap_or(0);
+            usage.allocation_count = allocation_count.unwrap_or(0);
+            usage.heap_index
+        };
+        if self.heap_usage(heap_index).usage() < self.warning_fraction {
+            self.warned[heap_index as usize] = false;
+        }
+    }
+
+    pub fn heap_usage(&self, heap_index: vk::uint32_t) -> HeapUsage {
+        let (allocated, allocation_count) = self.memory_types
+            .iter()
+            .filter(|usage| usage.heap_index == heap_index)
+            .fold((0, 0), |(allocated, count), usage| {
+                (allocated + usage.allocated, count + usage.allocation_count)
+            });
+        HeapUsage {
+            heap_index,
+            size: self.memory_properties.memory_heaps[heap_index as usize].size,
+            allocated,
+            allocation_count,
+        }
+    }
+
+    pub fn statistics(&self) -> MemoryStatistics {
+        MemoryStatistics {
+            heaps: (0..self.memory_properties.memory_heap_count)
+                .map(|heap_index| self.heap_usage(heap_index))
+                .collect(),
+            memory_types: self.memory_types.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use vk;
+    use super::MemoryUsage;
+
+    /// A discrete GPU with a device local heap, a small host visible window into it and a host
+    /// heap, with the memory types in the order drivers usually report them.
+    fn discrete_properties() -> vk::PhysicalDeviceMemoryProperties {
+        let device_local = vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT;
+        let host_visible = vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT;
+        let host_coherent = vk::MEMORY_PROPERTY_HOST_COHERENT_BIT;
+        let host_cached = vk::MEMORY_PROPERTY_HOST_CACHED_BIT;
+        properties(&[(device_local, 0),
+                     (device_local | host_visible | host_coherent, 1),
+                     (host_visible | host_coherent, 2),
+                     (host_visible | host_coherent | host_cached, 2)])
+    }
+
+    fn properties(types: &[(vk::MemoryPropertyFlags, vk::uint32_t)])
+                  -> vk::PhysicalDeviceMemoryProperties {
+        // Every field is an integer or a flag, so all zeroes are valid.
+        let mut properties: vk::PhysicalDeviceMemoryProperties = unsafe { mem::zeroed() };
+        properties.memory_type_count = types.len() as vk::uint32_t;
+        for (memory_type, &(property_flags, heap_index)) in
+            properties.memory_types.iter_mut().zip(types) {
+            memory_type.property_flags = property_flags;
+            memory_type.heap_index = heap_index;
+        }
+        properties.memory_heap_count = 3;
+        for (heap, &size) in properties.memory_heaps.iter_mut().zip(&[1 << 32, 1 << 28, 1 << 34]) {
+            heap.size = size;
+        }
+        properties
+    }
+
+    fn index_for(properties: &vk::PhysicalDeviceMemoryProperties,
+                 memory_type_bits: vk::uint32_t,
+                 usage: MemoryUsage)
+                 -> Option<vk::uint32_t> {
+        properties.find_memory_type_for_usage(memory_type_bits, usage).map(|info| info.index)
+    }
+
+    #[test]
+    fn gpu_only_memory_is_device_local() {
+        assert_eq!(index_for(&discrete_properties(), !0, MemoryUsage::GpuOnly), Some(0));
+    }
+
+    #[test]
+    fn cpu_to_gpu_memory_avoids_device_local_types() {
+        let properties = discrete_properties();
+        assert_eq!(index_for(&properties, !0, MemoryUsage::CpuToGpu), Some(2));
+        // The host visible device local type is still used when it is the only one allowed.
+        assert_eq!(index_for(&properties, 0b0011, MemoryUsage::CpuToGpu), Some(1));
+    }
+
+    #[test]
+    fn cpu_to_gpu_memory_prefers_coherent_types() {
+        let device_local = vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT;
+        let host_visible = vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT;
+        let host_coherent = vk::MEMORY_PROPERTY_HOST_COHERENT_BIT;
+        let properties = properties(&[(host_visible, 2),
+                                      (device_local | host_visible | host_coherent, 1)]);
+        assert_eq!(index_for(&properties, !0, MemoryUsage::CpuToGpu), Some(1));
+    }
+
+    #[test]
+    fn gpu_to_cpu_memory_prefers_cached_types() {
+        assert_eq!(index_for(&discrete_properties(), !0, MemoryUsage::GpuToCpu), Some(3));
+    }
+
+    #[test]
+    fn required_flags_and_type_bits_are_respected() {
+        let properties = discrete_properties();
+        assert_eq!(index_for(&properties, 0b0001, MemoryUsage::CpuToGpu), None);
+        assert_eq!(index_for(&properties, 0b0100, MemoryUsage::GpuOnly), None);
+    }
+}
@@ -0,0 +1,549 @@
+use prelude::*;
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::ptr;
+use std::slice;
+use vk;
+use device::Device;
+
+/// A scalar type that can be the value of a specialization constant.
+pub trait SpecializationValue: Copy {
+    /// How the value is laid out in the specialization data.
+    type Raw: Copy;
+    fn raw(self) -> Self::Raw;
+}
+
+/// Stored as a `vk::Bool32`, like `bool` constants in SPIR-V.
+impl SpecializationValue for bool {
+    type Raw = vk::Bool32;
+    fn raw(self) -> vk::Bool32 {
+        self as vk::Bool32
+    }
+}
+
+macro_rules! specialization_value {
+    ($($ty: ty),*) => {
+        $(
+            impl SpecializationValue for $ty {
+                type Raw = $ty;
+                fn raw(self) -> $ty {
+                    self
+                }
+            }
+        )*
+    }
+}
+
+specialization_value!(i32, u32, f32, f64);
+
+/// Packs specialization constants into a data blob with the matching map entries.
+#[derive(Debug, Clone, Default)]
+pub struct SpecializationConstants {
+    entries: Vec<vk::SpecializationMapEntry>,
+    data: Vec<u8>,
+}
+
+impl SpecializationConstants {
+    pub fn new() -> SpecializationConstants {
+        SpecializationConstants::default()
+    }
+
+    /// Sets the constant with `constant_id` to `value`, replacing an earlier value.
+    pub fn constant<T: SpecializationValue>(mut self,
+                                            constant_id: vk::uint32_t,
+                                            value: T)
+                                            -> SpecializationConstants {
+        let raw = value.raw();
+        let size = mem::size_of::<T::Raw>();
+        let bytes = unsafe { slice::from_raw_parts(&raw as *const T::Raw as *const u8, size) };
+        match self.entries.iter().position(|entry| entry.constant_id == constant_id) {
+            Some(index) if self.entries[index].size == size => {
+                let offset = self.entries[index].offset as usize;
+                self.data[offset..offset + size].copy_from_slice(bytes);
+                return self;
+            }
+            Some(index) => {
+                self.entries.remove(index);
+                self.repack();
+            }
+            None => {}
+        }
+        let offset = self.push_data(bytes);
+        self.entries.push(vk::SpecializationMapEntry {
+            constant_id,
+            offset,
+            size,
+        });
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Appends `bytes` to the data, aligned to their size, and returns their offset.
+    fn push_data(&mut self, bytes: &[u8]) -> vk::uint32_t {
+        let offset = self.data.len().div_ceil(bytes.len()) * bytes.len();
+        self.data.resize(offset, 0);
+        self.data.extend_from_slice(bytes);
+        offset as vk::uint32_t
+    }
+
+    /// Rebuilds the data from the entries, dropping the bytes of removed constants.
+    fn repack(&mut self) {
+        let data = mem::take(&mut self.data);
+        let mut entries = mem::take(&mut self.entries);
+        for entry in &mut entries {
+            let start = entry.offset as usize;
+            entry.offset = self.push_data(&data[start..start + entry.size]);
+        }
+        self.entries = entries;
+    }
+
+    /// Describes the constants. The returned struct points into `self`, so it is only used by
+    /// the pipeline builders, which keep `self` alive until the pipeline is created.
+    fn info(&self) -> vk::SpecializationInfo {
+        vk::SpecializationInfo {
+            map_entry_count: self.entries.len() as vk::uint32_t,
+            p_map_entries: self.entries.as_ptr(),
+            data_size: self.data.len(),
+            p_data: self.data.as_ptr() as *const vk::c_void,
+        }
+    }
+}
+
+struct ShaderStage {
+    stage: vk::ShaderStageFlags,
+    module: vk::ShaderModule,
+    entry_name: CString,
+    constants: SpecializationConstants,
+}
+
+impl ShaderStage {
+    fn new(stage: vk::ShaderStageFlags,
+           module: vk::ShaderModule,
+           entry_name: &CStr,
+           constants: SpecializationConstants)
+           -> ShaderStage {
+        ShaderStage {
+            stage,
+            module,
+            entry_name: entry_name.to_owned(),
+            constants,
+        }
+    }
+
+    /// `specialization_info` has to be `self.constants.info()`, it is passed in so that it
+    /// outlives the returned struct.
+    fn create_info(&self,
+                   specialization_info: &vk::SpecializationInfo)
+                   -> vk::PipelineShaderStageCreateInfo {
+        vk::PipelineShaderStageCreateInfo {
+            s_type: vk::StructureType::PipelineShaderStageCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            stage: self.stage,
+            module: self.module,
+            p_name: self.entry_name.as_ptr(),
+            p_specialization_info: if self.constants.is_empty() {
+                ptr::null()
+            } else {
+                specialization_info
+            },
+        }
+    }
+}
+
+/// Builds a `vk::GraphicsPipelineCreateInfo` from owned state, so no pointer can outlive what
+/// it points to.
+///
+/// The defaults are a triangle list, filled polygons without culling, no depth test, a single
+/// color attachment without blending, one sample, and a dynamic viewport and scissor.
+pub struct GraphicsPipelineBuilder {
+    stages: Vec<ShaderStage>,
+    vertex_bindings: Vec<vk::VertexInputBindingDescription>,
+    vertex_attributes: Vec<vk::VertexInputAttributeDescription>,
+    topology: vk::PrimitiveTopology,
+    primitive_restart: bool,
+    viewport: Option<(vk::Viewport, vk::Rect2D)>,
+    polygon_mode: vk::PolygonMode,
+    cull_mode: vk::CullModeFlags,
+    front_face: vk::FrontFace,
+    line_width: f32,
+    samples: vk::SampleCountFlags,
+    depth_test: Option<(vk::CompareOp, bool)>,
+    color_blend_attachments: Vec<vk::PipelineColorBlendAttachmentState>,
+    dynamic_states: Vec<vk::DynamicState>,
+    layout: vk::PipelineLayout,
+    render_pass: vk::RenderPass,
+    subpass: vk::uint32_t,
+}
+
+impl GraphicsPipelineBuilder {
+    pub fn new(layout: vk::PipelineLayout,
+               render_pass: vk::RenderPass,
+               subpass: vk::uint32_t)
+               -> GraphicsPipelineBuilder {
+        GraphicsPipelineBuilder {
+            stages: Vec::new(),
+            vertex_bindings: Vec::new(),
+            vertex_attributes: Vec::new(),
+            topology: vk::PrimitiveTopology::TriangleList,
+            primitive_restart: false,
+            viewport: None,
+            polygon_mode: vk::PolygonMode::Fill,
+            cull_mode: vk::CULL_MODE_NONE,
+            front_face: vk::FrontFace::CounterClockwise,
+            line_width: 1.0,
+            samples: vk::SAMPLE_COUNT_1_BIT,
+            depth_test: None,
+            color_blend_attachments: vec![opaque_attachment()],
+            dynamic_states: vec![vk::DynamicState::Viewport, vk::DynamicState::Scissor],
+            layout,
+            render_pass,
+            subpass,
+        }
+    }
+
+    /// Adds a shader stage that starts at `entry_name` in `module`.
+    pub fn stage(mut self,
+                 stage: vk::ShaderStageFlags,
+                 module: vk::ShaderModule,
+                 entry_name: &CStr)
+                 -> GraphicsPipelineBuilder {
+        self.stages.push(ShaderStage::new(stage,
+                                          module,
+                                          entry_name,
+                                          SpecializationConstants::new()));
+        self
+    }
+
+    /// Like `stage`, with specialization constants.
+    pub fn specialized_stage(mut self,
+                             stage: vk::ShaderStageFlags,
+                             module: vk::ShaderModule,
+                             entry_name: &CStr,
+                             constants: SpecializationConstants)
+                             -> GraphicsPipelineBuilder {
+        self.stages.push(ShaderStage::new(stage, module, entry_name, constants));
+        self
+    }
+
+    pub fn vertex_binding(mut self,
+                          binding: vk::uint32_t,
+                          stride: vk::uint32_t,
+                          input_rate: vk::VertexInputRate)
+                          -> GraphicsPipelineBuilder {
+        self.vertex_bindings.push(vk::VertexInputBindingDescription {
+            binding,
+            stride,
+            input_rate,
+        });
+        self
+    }
+
+    pub fn vertex_attribute(mut self,
+                            location: vk::uint32_t,
+                            binding: vk::uint32_t,
+                            format: vk::Format,
+                            offset: vk::uint32_t)
+                            -> GraphicsPipelineBuilder {
+        self.vertex_attributes.push(vk::VertexInputAttributeDescription {
+            location,
+            binding,
+            format,
+            offset,
+        });
+        self
+    }
+
+    pub fn topology(mut self,
+                    topology: vk::PrimitiveTopology,
+                    primitive_restart: bool)
+                    -> GraphicsPipelineBuilder {
+        self.topology = topology;
+        self.primitive_restart = primitive_restart;
+        self
+    }
+
+    /// Bakes the viewport and scissor into the pipeline instead of leaving them dynamic.
+    pub fn viewport(mut self,
+                    viewport: vk::Viewport,
+                    scissor: vk::Rect2D)
+                    -> GraphicsPipelineBuilder {
+        self.viewport = Some((viewport, scissor));
+        self.dynamic_states.retain(|&state| {
+            state != vk::DynamicState::Viewport && state != vk::DynamicState::Scissor
+        });
+        self
+    }
+
+    pub fn polygon_mode(mut self, polygon_mode: vk::PolygonMode) -> GraphicsPipelineBuilder {
+        self.polygon_mode = polygon_mode;
+        self
+    }
+
+    pub fn cull_mode(mut self,
+                     cull_mode: vk::CullModeFlags,
+                     front_face: vk::FrontFace)
+                     -> GraphicsPipelineBuilder {
+        self.cull_mode = cull_mode;
+        self.front_face = front_face;
+        self
+    }
+
+    pub fn line_width(mut self, line_width: f32) -> GraphicsPipelineBuilder {
+        self.line_width = line_width;
+        self
+    }
+
+    pub fn samples(mut self, samples: vk::SampleCountFlags) -> GraphicsPipelineBuilder {
+        self.samples = samples;
+        self
+    }
+
+    /// Enables the depth test with `compare_op`, and depth writes if `write` is true.
+    pub fn depth_test(mut self, compare_op: vk::CompareOp, write: bool) -> GraphicsPipelineBuilder {
+        self.depth_test = Some((compare_op, write));
+        self
+    }
+
+    /// Uses one blend state per color attachment of the subpass, replacing the default of a
+    /// single attachment without blending.
+    pub fn color_blend_attachments(mut self,
+                                   attachments: &[vk::PipelineColorBlendAttachmentState])
+                                   -> GraphicsPipelineBuilder {
+        self.color_blend_attachments = attachments.to_vec();
+        self
+    }
+
+    /// `count` color attachments without blending.
+    pub fn opaque_color_attachments(mut self, count: usize) -> GraphicsPipelineBuilder {
+        self.color_blend_attachments = vec![opaque_attachment(); count];
+        self
+    }
+
+    /// Adds a dynamic state, viewport and scissor are dynamic unless `viewport` was called.
+    pub fn dynamic_state(mut self, state: vk::DynamicState) -> GraphicsPipelineBuilder {
+        if !self.dynamic_states.contains(&state) {
+            self.dynamic_states.push(state);
+        }
+        self
+    }
+
+    pub fn build(&self,
+                 device: &Device,
+                 pipeline_cache: vk::PipelineCache)
+                 -> VkResult<vk::Pipeline> {
+        let specialization_infos: Vec<vk::SpecializationInfo> = self.stages
+            .iter()
+            .map(|stage| stage.constants.info())
+            .collect();
+        let stages: Vec<vk::PipelineShaderStageCreateInfo> = self.stages
+            .iter()
+            .zip(&specialization_infos)
+            .map(|(stage, specialization_info)| stage.create_info(specialization_info))
+            .collect();
+        let vertex_input_state = vk::PipelineVertexInputStateCreateInfo {
+            s_type: vk::StructureType::PipelineVertexInputStateCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            vertex_binding_description_count: self.vertex_bindings.len() as vk::uint32_t,
+            p_vertex_binding_descriptions: self.vertex_bindings.as_ptr(),
+            vertex_attribute_description_count: self.vertex_attributes.len() as vk::uint32_t,
+            p_vertex_attribute_descriptions: self.vertex_attributes.as_ptr(),
+        };
+        let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo {
+            s_type: vk::StructureType::PipelineInputAssemblyStateCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            topology: self.topology,
+            primitive_restart_enable: self.primitive_restart as vk::Bool32,
+        };
+        // With a dynamic viewport and scissor only the counts are used.
+        let viewport_state = vk::PipelineViewportStateCreateInfo {
+            s_type: vk::StructureType::PipelineViewportStateCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            viewport_count: 1,
+            p_viewports: self.viewport.as_ref().map_or(ptr::null(), |(viewport, _)| viewport),
+            scissor_count: 1,
+            p_scissors: self.viewport.as_ref().map_or(ptr::null(), |(_, scissor)| scissor),
+        };
+        let rasterization_state = vk::PipelineRasterizationStateCreateInfo {
+            s_type: vk::StructureType::PipelineRasterizationStateCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            depth_clamp_enable: 0,
+            rasterizer_discard_enable: 0,
+            polygon_mode: self.polygon_mode,
+            cull_mode: self.cull_mode,
+            front_face: self.front_face,
+            depth_bias_enable: 0,
+            depth_bias_constant_factor: 0.0,
+            depth_bias_clamp: 0.0,
+            depth_bias_slope_factor: 0.0,
+            line_width: self.line_width,
+        };
+        let multisample_state = vk::PipelineMultisampleStateCreateInfo {
+            s_type: vk::StructureType::PipelineMultisampleStateCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            rasterization_samples: self.samples,
+            sample_shading_enable: 0,
+            min_sample_shading: 0.0,
+            p_sample_mask: ptr::null(),
+            alpha_to_coverage_enable: 0,
+            alpha_to_one_enable: 0,
+        };
+        let noop_stencil_state = vk::StencilOpState {
+            fail_op: vk::StencilOp::Keep,
+            pass_op: vk::StencilOp::Keep,
+            depth_fail_op: vk::StencilOp::Keep,
+            compare_op: vk::CompareOp::Always,
+            compare_mask: 0,
+            write_mask: 0,
+            reference: 0,
+        };
+        let (depth_compare_op, depth_write) = self.depth_test
+            .unwrap_or((vk::CompareOp::Always, false));
+        let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo {
+            s_type: vk::StructureType::PipelineDepthStencilStateCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            depth_test_enable: self.depth_test.is_some() as vk::Bool32,
+            depth_write_enable: depth_write as vk::Bool32,
+            depth_compare_op,
+            depth_bounds_test_enable: 0,
+            stencil_test_enable: 0,
+            front: noop_stencil_state.clone(),
+            back: noop_stencil_state,
+            min_depth_bounds: 0.0,
+            max_depth_bounds: 1.0,
+        };
+        let color_blend_state = vk::PipelineColorBlendStateCreateInfo {
+            s_type: vk::StructureType::PipelineColorBlendStateCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            logic_op_enable: 0,
+            logic_op: vk::LogicOp::Clear,
+            attachment_count: self.color_blend_attachments.len() as vk::uint32_t,
+            p_attachments: self.color_blend_attachments.as_ptr(),
+            blend_constants: [0.0, 0.0, 0.0, 0.0],
+        };
+        let dynamic_state = vk::PipelineDynamicStateCreateInfo {
+            s_type: vk::StructureType::PipelineDynamicStateCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            dynamic_state_count: self.dynamic_states.len() as vk::uint32_t,
+            p_dynamic_states: self.dynamic_states.as_ptr(),
+        };
+        let create_info = vk::GraphicsPipelineCreateInfo {
+            s_type: vk::StructureType::GraphicsPipelineCreateInfo,
+            p_next: ptr::null(),
+            flags: vk::PipelineCreateFlags::empty(),
+            stage_count: stages.len() as vk::uint32_t,
+            p_stages: stages.as_ptr(),
+            p_vertex_input_state: &vertex_input_state,
+            p_input_assembly_state: &input_assembly_state,
+            p_tessellation_state: ptr::null(),
+            p_viewport_state: &viewport_state,
+            p_rasterization_state: &rasterization_state,
+            p_multisample_state: &multisample_state,
+            p_depth_stencil_state: &depth_stencil_state,
+            p_color_blend_state: &color_blend_state,
+            p_dynamic_state: if self.dynamic_states.is_empty() {
+                ptr::null()
+            } else {
+                &dynamic_state
+            },
+            layout: self.layout,
+            render_pass: self.render_pass,
+            subpass: self.subpass,
+            base_pipeline_handle: vk::Pipeline::null(),
+            base_pipeline_index: -1,
+        };
+        let pipelines = device.create_graphics_pipelines(pipeline_cache, &[create_info])?;
+        Ok(pipelines[0])
+    }
+}
+
+/// Builds a compute pipeline from a single shader stage.
+pub struct ComputePipelineBuilder {
+    stage: ShaderStage,
+    layout: vk::PipelineLayout,
+}
+
+impl ComputePipelineBuilder {
+    pub fn new(layout: vk::PipelineLayout,
+               module: vk::ShaderModule,
+               entry_name: &CStr)
+               -> ComputePipelineBuilder {
+        ComputePipelineBuilder {
+            stage: ShaderStage::new(vk::SHADER_STAGE_COMPUTE_BIT,
+                                    module,
+                                    entry_name,
+                                    SpecializationConstants::new()),
+            layout,
+        }
+    }
+
+    pub fn specialization(mut self, constants: SpecializationConstants) -> ComputePipelineBuilder {
+        self.stage.constants = constants;
+        self
+    }
+
+    pub fn build(&self,
+                 device: &Device,
+                 pipeline_cache: vk::PipelineCache)
+                 -> VkResult<vk::Pipeline> {
+        let specialization_info = self.stage.constants.info();
+        let create_info = vk::ComputePipelineCreateInfo {
+            s_type: vk::StructureType::ComputePipelineCreateInfo,
+            p_next: ptr::null(),
+            flags: vk::PipelineCreateFlags::empty(),
+            stage: self.stage.create_info(&specialization_info),
+            layout: self.layout,
+            base_pipeline_handle: vk::Pipeline::null(),
+            base_pipeline_index: -1,
+        };
+        let pipelines = device.create_compute_pipelines(pipeline_cache, &[create_info])?;
+        Ok(pipelines[0])
+    }
+}
+
+fn opaque_attachment() -> vk::PipelineColorBlendAttachmentState {
+    vk::PipelineColorBlendAttachmentState {
+        blend_enable: 0,
+        src_color_blend_factor: vk::BlendFactor::One,
+        dst_color_blend_factor: vk::BlendFactor::Zero,
+        color_blend_op: vk::BlendOp::Add,
+        src_alpha_blend_factor: vk::BlendFactor::One,
+        dst_alpha_blend_factor: vk::BlendFactor::Zero,
+        alpha_blend_op: vk::BlendOp::Add,
+        color_write_mask: vk::ColorComponentFlags::all(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SpecializationConstants;
+
+    #[test]
+    fn replacing_a_constant_keeps_the_data_compact() {
+        let constants = SpecializationConstants::new()
+            .constant(0, 1u32)
+            .constant(1, 2.0f64)
+            .constant(0, 3u32)
+            .constant(1, true)
+            .constant(2, 4i32);
+        // 0 stays at offset 0, 1 is moved to offset 4 when it shrinks, 2 follows it.
+        let layout: Vec<_> = constants.entries
+            .iter()
+            .map(|entry| (entry.constant_id, entry.offset, entry.size))
+            .collect();
+        assert_eq!(layout, vec![(0, 0, 4), (1, 4, 4), (2, 8, 4)]);
+        assert_eq!(constants.data.len(), 12);
+        assert_eq!(&constants.data[0..4], &[3, 0, 0, 0]);
+    }
+}
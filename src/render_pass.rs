@@ -0,0 +1,553 @@
+use std::collections::HashMap;
+use std::ptr;
+use vk;
+use device::Device;
+
+#[derive(Debug)]
+pub enum RenderPassError {
+    /// A subpass uses an attachment name that was never added.
+    UnknownAttachment(String),
+    VkError(vk::Result),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AttachmentUse {
+    Color,
+    Resolve,
+    Depth,
+    DepthReadOnly,
+    Input,
+}
+
+impl AttachmentUse {
+    fn layout(self) -> vk::ImageLayout {
+        match self {
+            AttachmentUse::Color | AttachmentUse::Resolve => {
+                vk::ImageLayout::ColorAttachmentOptimal
+            }
+            AttachmentUse::Depth => vk::ImageLayout::DepthStencilAttachmentOptimal,
+            AttachmentUse::DepthReadOnly => vk::ImageLayout::DepthStencilReadOnlyOptimal,
+            AttachmentUse::Input => vk::ImageLayout::ShaderReadOnlyOptimal,
+        }
+    }
+
+    fn stages(self) -> vk::PipelineStageFlags {
+        match self {
+            AttachmentUse::Color | AttachmentUse::Resolve => {
+                vk::PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT
+            }
+            AttachmentUse::Depth | AttachmentUse::DepthReadOnly => {
+                vk::PIPELINE_STAGE_EARLY_FRAGMENT_TESTS_BIT |
+                vk::PIPELINE_STAGE_LATE_FRAGMENT_TESTS_BIT
+            }
+            AttachmentUse::Input => vk::PIPELINE_STAGE_FRAGMENT_SHADER_BIT,
+        }
+    }
+
+    fn access(self) -> vk::AccessFlags {
+        match self {
+            AttachmentUse::Color | AttachmentUse::Resolve => {
+                vk::ACCESS_COLOR_ATTACHMENT_READ_BIT | vk::ACCESS_COLOR_ATTACHMENT_WRITE_BIT
+            }
+            AttachmentUse::Depth => {
+                vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_READ_BIT |
+                vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_WRITE_BIT
+            }
+            AttachmentUse::DepthReadOnly => vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_READ_BIT,
+            AttachmentUse::Input => vk::ACCESS_INPUT_ATTACHMENT_READ_BIT,
+        }
+    }
+
+    fn write_access(self) -> vk::AccessFlags {
+        match self {
+            AttachmentUse::Color | AttachmentUse::Resolve => vk::ACCESS_COLOR_ATTACHMENT_WRITE_BIT,
+            AttachmentUse::Depth => vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_WRITE_BIT,
+            AttachmentUse::DepthReadOnly | AttachmentUse::Input => vk::AccessFlags::empty(),
+        }
+    }
+
+    /// The stages and access of whatever wrote the attachment before the render pass.
+    fn external_source(self) -> (vk::PipelineStageFlags, vk::AccessFlags) {
+        match self {
+            AttachmentUse::Color | AttachmentUse::Resolve => {
+                (vk::PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT,
+                 vk::ACCESS_COLOR_ATTACHMENT_WRITE_BIT)
+            }
+            AttachmentUse::Depth | AttachmentUse::DepthReadOnly => {
+                (AttachmentUse::Depth.stages(), vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_WRITE_BIT)
+            }
+            AttachmentUse::Input => {
+                (vk::PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT |
+                 vk::PIPELINE_STAGE_LATE_FRAGMENT_TESTS_BIT,
+                 vk::ACCESS_COLOR_ATTACHMENT_WRITE_BIT |
+                 vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_WRITE_BIT)
+            }
+        }
+    }
+}
+
+/// The attachments one subpass uses, by name.
+#[derive(Debug, Clone, Default)]
+pub struct Subpass {
+    colors: Vec<(String, Option<String>)>,
+    depth: Option<(String, bool)>,
+    inputs: Vec<String>,
+}
+
+impl Subpass {
+    pub fn new() -> Subpass {
+        Subpass::default()
+    }
+
+    pub fn color(mut self, name: &str) -> Subpass {
+        self.colors.push((name.to_owned(), None));
+        self
+    }
+
+    /// A multisampled color attachment that is resolved into `resolve`.
+    pub fn color_resolve(mut self, name: &str, resolve: &str) -> Subpass {
+        self.colors.push((name.to_owned(), Some(resolve.to_owned())));
+        self
+    }
+
+    pub fn depth(mut self, name: &str) -> Subpass {
+        self.depth = Some((name.to_owned(), false));
+        self
+    }
+
+    /// A depth attachment that is tested against but not written.
+    pub fn depth_read_only(mut self, name: &str) -> Subpass {
+        self.depth = Some((name.to_owned(), true));
+        self
+    }
+
+    pub fn input(mut self, name: &str) -> Subpass {
+        self.inputs.push(name.to_owned());
+        self
+    }
+
+    fn uses(&self) -> Vec<(&str, AttachmentUse)> {
+        let mut uses = Vec::new();
+        for (color, resolve) in &self.colors {
+            uses.push((&color[..], AttachmentUse::Color));
+            if let Some(ref resolve) = *resolve {
+                uses.push((&resolve[..], AttachmentUse::Resolve));
+            }
+        }
+        if let Some((ref depth, read_only)) = self.depth {
+            let usage = if read_only {
+                AttachmentUse::DepthReadOnly
+            } else {
+                AttachmentUse::Depth
+            };
+            uses.push((&depth[..], usage));
+        }
+        for input in &self.inputs {
+            uses.push((&input[..], AttachmentUse::Input));
+        }
+        uses
+    }
+}
+
+/// Builds a render pass from named attachments and the subpasses that use them.
+///
+/// The subpass dependencies are inferred: one from `VK_SUBPASS_EXTERNAL` for the first use of
+/// every attachment, one between subpasses whenever a subpass uses an attachment that an
+/// earlier one wrote or used in another layout, and one to `VK_SUBPASS_EXTERNAL` if the final
+/// layout is meant for sampling, transfers or attachment use after the render pass. Attachments
+/// that are used before and after a subpass but not in it are preserved.
+#[derive(Debug, Clone, Default)]
+pub struct RenderPassBuilder {
+    attachments: Vec<(String, vk::AttachmentDescription)>,
+    subpasses: Vec<Subpass>,
+    dependencies: Vec<vk::SubpassDependency>,
+}
+
+impl RenderPassBuilder {
+    pub fn new() -> RenderPassBuilder {
+        RenderPassBuilder::default()
+    }
+
+    pub fn attachment(mut self,
+                      name: &str,
+                      description: vk::AttachmentDescription)
+                      -> RenderPassBuilder {
+        self.attachments.push((name.to_owned(), description));
+        self
+    }
+
+    pub fn subpass(mut self, subpass: Subpass) -> RenderPassBuilder {
+        self.subpasses.push(subpass);
+        self
+    }
+
+    /// Adds a dependency on top of the inferred ones, e.g. for resources other than
+    /// attachments.
+    pub fn dependency(mut self, dependency: vk::SubpassDependency) -> RenderPassBuilder {
+        self.dependencies.push(dependency);
+        self
+    }
+
+    /// The index of the attachment called `name` in the render pass, which is also its index
+    /// in the framebuffer.
+    pub fn attachment_index(&self, name: &str) -> Option<vk::uint32_t> {
+        self.attachments
+            .iter()
+            .position(|(other, _)| other == name)
+            .map(|index| index as vk::uint32_t)
+    }
+
+    pub fn build(&self, device: &Device) -> Result<vk::RenderPass, RenderPassError> {
+        let uses = self.attachment_uses()?;
+        let mut dependencies = infer_dependencies(&self.attachments, &uses);
+        dependencies.extend(self.dependencies.iter().cloned());
+
+        // The references have to stay alive until the render pass is created.
+        let mut color_references = Vec::with_capacity(self.subpasses.len());
+        let mut resolve_references = Vec::with_capacity(self.subpasses.len());
+        let mut depth_references = Vec::with_capacity(self.subpasses.len());
+        let mut input_references = Vec::with_capacity(self.subpasses.len());
+        let mut preserved = Vec::with_capacity(self.subpasses.len());
+        for (subpass, description) in self.subpasses.iter().enumerate() {
+            let mut colors = Vec::new();
+            let mut resolves = Vec::new();
+            for (color, resolve) in &description.colors {
+                colors.push(self.reference(color, AttachmentUse::Color)?);
+                resolves.push(match *resolve {
+                    Some(ref resolve) => self.reference(resolve, AttachmentUse::Resolve)?,
+                    None => unused_reference(),
+                });
+            }
+            if resolves.iter().all(|reference| reference.attachment == vk::VK_ATTACHMENT_UNUSED) {
+                resolves.clear();
+            }
+            color_references.push(colors);
+            resolve_references.push(resolves);
+            depth_references.push(match description.depth {
+                Some((ref depth, false)) => Some(self.reference(depth, AttachmentUse::Depth)?),
+                Some((ref depth, true)) => {
+                    Some(self.reference(depth, AttachmentUse::DepthReadOnly)?)
+                }
+                None => None,
+            });
+            let mut inputs = Vec::new();
+            for input in &description.inputs {
+                inputs.push(self.reference(input, AttachmentUse::Input)?);
+            }
+            input_references.push(inputs);
+            preserved.push(uses.iter()
+                .enumerate()
+                .filter(|&(_, attachment_uses)| {
+                    attachment_uses.first().is_some_and(|&(first, _)| first < subpass) &&
+                    attachment_uses.last().is_some_and(|&(last, _)| last > subpass) &&
+                    attachment_uses.iter().all(|&(other, _)| other != subpass)
+                })
+                .map(|(index, _)| index as vk::uint32_t)
+                .collect::<Vec<_>>());
+        }
+        let subpasses: Vec<vk::SubpassDescription> = (0..self.subpasses.len())
+            .map(|subpass| {
+                vk::SubpassDescription {
+                    flags: Default::default(),
+                    pipeline_bind_point: vk::PipelineBindPoint::Graphics,
+                    input_attachment_count: input_references[subpass].len() as vk::uint32_t,
+                    p_input_attachments: input_references[subpass].as_ptr(),
+                    color_attachment_count: color_references[subpass].len() as vk::uint32_t,
+                    p_color_attachments: color_references[subpass].as_ptr(),
+                    p_resolve_attachments: if resolve_references[subpass].is_empty() {
+                        ptr::null()
+                    } else {
+                        resolve_references[subpass].as_ptr()
+                    },
+                    p_depth_stencil_attachment: depth_references[subpass]
+                        .as_ref()
+                        .map_or(ptr::null(), |reference| reference),
+                    preserve_attachment_count: preserved[subpass].len() as vk::uint32_t,
+                    p_preserve_attachments: preserved[subpass].as_ptr(),
+                }
+            })
+            .collect();
+        let attachments: Vec<vk::AttachmentDescription> = self.attachments
+            .iter()
+            .map(|(_, description)| description.clone())
+            .collect();
+        let create_info = vk::RenderPassCreateInfo {
+            s_type: vk::StructureType::RenderPassCreateInfo,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            attachment_count: attachments.len() as vk::uint32_t,
+            p_attachments: attachments.as_ptr(),
+            subpass_count: subpasses.len() as vk::uint32_t,
+            p_subpasses: subpasses.as_ptr(),
+            dependency_count: dependencies.len() as vk::uint32_t,
+            p_dependencies: dependencies.as_ptr(),
+        };
+        device.create_render_pass(&create_info).map_err(RenderPassError::VkError)
+    }
+
+    /// The uses of every attachment, as (subpass, use), in subpass order.
+    fn attachment_uses(&self) -> Result<Vec<Vec<(usize, AttachmentUse)>>, RenderPassError> {
+        let mut uses = vec![Vec::new(); self.attachments.len()];
+        for (subpass, description) in self.subpasses.iter().enumerate() {
+            for (name, usage) in description.uses() {
+                let index = self.index(name)? as usize;
+                uses[index].push((subpass, usage));
+            }
+        }
+        Ok(uses)
+    }
+
+    fn index(&self, name: &str) -> Result<vk::uint32_t, RenderPassError> {
+        self.attachment_index(name)
+            .ok_or_else(|| RenderPassError::UnknownAttachment(name.to_owned()))
+    }
+
+    fn reference(&self,
+                 name: &str,
+                 usage: AttachmentUse)
+                 -> Result<vk::AttachmentReference, RenderPassError> {
+        Ok(vk::AttachmentReference {
+            attachment: self.index(name)?,
+            layout: usage.layout(),
+        })
+    }
+}
+
+/// Infers the subpass dependencies from the `uses` of every attachment, as returned by
+/// `RenderPassBuilder::attachment_uses`.
+fn infer_dependencies(attachments: &[(String, vk::AttachmentDescription)],
+                      uses: &[Vec<(usize, AttachmentUse)>])
+                      -> Vec<vk::SubpassDependency> {
+    let mut dependencies = Dependencies::new();
+    for (index, attachment_uses) in uses.iter().enumerate() {
+        let description = &attachments[index].1;
+        let mut previous: Option<(usize, AttachmentUse)> = None;
+        for &(subpass, usage) in attachment_uses {
+            match previous {
+                None => {
+                    let (src_stages, src_access) = usage.external_source();
+                    dependencies.add(vk::VK_SUBPASS_EXTERNAL,
+                                     subpass as vk::uint32_t,
+                                     src_stages,
+                                     src_access,
+                                     usage.stages(),
+                                     usage.access());
+                }
+                Some((previous_subpass, previous_usage)) if previous_subpass != subpass => {
+                    if !previous_usage.write_access().is_empty() ||
+                       !usage.write_access().is_empty() ||
+                       previous_usage.layout() != usage.layout() {
+                        dependencies.add(previous_subpass as vk::uint32_t,
+                                         subpass as vk::uint32_t,
+                                         previous_usage.stages(),
+                                         previous_usage.write_access(),
+                                         usage.stages(),
+                                         usage.access());
+                    }
+                }
+                Some(_) => {}
+            }
+            previous = Some((subpass, usage));
+        }
+        if let Some((subpass, usage)) = previous {
+            if let Some((dst_stages, dst_access)) = final_use(description.final_layout) {
+                dependencies.add(subpass as vk::uint32_t,
+                                 vk::VK_SUBPASS_EXTERNAL,
+                                 usage.stages(),
+                                 usage.write_access(),
+                                 dst_stages,
+                                 dst_access);
+            }
+        }
+    }
+    dependencies.into_vec()
+}
+
+/// Collects dependencies, merging those between the same pair of subpasses.
+struct Dependencies {
+    order: Vec<(vk::uint32_t, vk::uint32_t)>,
+    dependencies: HashMap<(vk::uint32_t, vk::uint32_t), vk::SubpassDependency>,
+}
+
+impl Dependencies {
+    fn new() -> Dependencies {
+        Dependencies {
+            order: Vec::new(),
+            dependencies: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self,
+           src_subpass: vk::uint32_t,
+           dst_subpass: vk::uint32_t,
+           src_stage_mask: vk::PipelineStageFlags,
+           src_access_mask: vk::AccessFlags,
+           dst_stage_mask: vk::PipelineStageFlags,
+           dst_access_mask: vk::AccessFlags) {
+        let key = (src_subpass, dst_subpass);
+        if !self.dependencies.contains_key(&key) {
+            self.order.push(key);
+        }
+        // Dependencies within the render pass only concern the same pixel.
+        let dependency_flags = if src_subpass != vk::VK_SUBPASS_EXTERNAL &&
+                                  dst_subpass != vk::VK_SUBPASS_EXTERNAL {
+            vk::DEPENDENCY_BY_REGION_BIT
+        } else {
+            vk::DependencyFlags::empty()
+        };
+        let dependency = self.dependencies.entry(key).or_insert(vk::SubpassDependency {
+            src_subpass,
+            dst_subpass,
+            src_stage_mask: vk::PipelineStageFlags::empty(),
+            dst_stage_mask: vk::PipelineStageFlags::empty(),
+            src_access_mask: vk::AccessFlags::empty(),
+            dst_access_mask: vk::AccessFlags::empty(),
+            dependency_flags,
+        });
+        dependency.src_stage_mask |= src_stage_mask;
+        dependency.src_access_mask |= src_access_mask;
+        dependency.dst_stage_mask |= dst_stage_mask;
+        dependency.dst_access_mask |= dst_access_mask;
+    }
+
+    fn into_vec(mut self) -> Vec<vk::SubpassDependency> {
+        let dependencies = &mut self.dependencies;
+        self.order.iter().map(|key| dependencies.remove(key).unwrap()).collect()
+    }
+}
+
+/// The stages and access that use an attachment after the render pass, judging by its final
+/// layout. Presentation is synchronized with a semaphore instead.
+///
+/// A shader read only attachment may be sampled by any later vertex, fragment or compute
+/// shader. Tessellation and geometry shaders are left out because naming their stages
+/// requires the matching device features; add a dependency for them with `dependency`.
+fn final_use(layout: vk::ImageLayout) -> Option<(vk::PipelineStageFlags, vk::AccessFlags)> {
+    match layout {
+        vk::ImageLayout::ShaderReadOnlyOptimal => {
+            Some((vk::PIPELINE_STAGE_VERTEX_SHADER_BIT | vk::PIPELINE_STAGE_FRAGMENT_SHADER_BIT |
+                  vk::PIPELINE_STAGE_COMPUTE_SHADER_BIT,
+                  vk::ACCESS_SHADER_READ_BIT))
+        }
+        vk::ImageLayout::TransferSrcOptimal => {
+            Some((vk::PIPELINE_STAGE_TRANSFER_BIT, vk::ACCESS_TRANSFER_READ_BIT))
+        }
+        vk::ImageLayout::ColorAttachmentOptimal => {
+            Some((AttachmentUse::Color.stages(), AttachmentUse::Color.access()))
+        }
+        vk::ImageLayout::DepthStencilAttachmentOptimal => {
+            Some((AttachmentUse::Depth.stages(), AttachmentUse::Depth.access()))
+        }
+        _ => None,
+    }
+}
+
+fn unused_reference() -> vk::AttachmentReference {
+    vk::AttachmentReference {
+        attachment: vk::VK_ATTACHMENT_UNUSED,
+        layout: vk::ImageLayout::Undefined,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vk;
+    use super::{infer_dependencies, RenderPassBuilder, Subpass};
+
+    fn attachment(final_layout: vk::ImageLayout) -> vk::AttachmentDescription {
+        vk::AttachmentDescription {
+            flags: vk::AttachmentDescriptionFlags::empty(),
+            format: vk::Format::R8g8b8a8Unorm,
+            samples: vk::SAMPLE_COUNT_1_BIT,
+            load_op: vk::AttachmentLoadOp::Clear,
+            store_op: vk::AttachmentStoreOp::Store,
+            stencil_load_op: vk::AttachmentLoadOp::DontCare,
+            stencil_store_op: vk::AttachmentStoreOp::DontCare,
+            initial_layout: vk::ImageLayout::Undefined,
+            final_layout,
+        }
+    }
+
+    fn dependencies(builder: &RenderPassBuilder) -> Vec<vk::SubpassDependency> {
+        infer_dependencies(&builder.attachments, &builder.attachment_uses().unwrap())
+    }
+
+    fn find(dependencies: &[vk::SubpassDependency],
+            src_subpass: vk::uint32_t,
+            dst_subpass: vk::uint32_t)
+            -> Option<&vk::SubpassDependency> {
+        dependencies.iter().find(|dependency| {
+            dependency.src_subpass == src_subpass && dependency.dst_subpass == dst_subpass
+        })
+    }
+
+    #[test]
+    fn first_uses_depend_on_external_writes() {
+        let builder = RenderPassBuilder::new()
+            .attachment("color", attachment(vk::ImageLayout::PresentSrcKhr))
+            .attachment("depth", attachment(vk::ImageLayout::DepthStencilAttachmentOptimal))
+            .subpass(Subpass::new().color("color").depth("depth"));
+        let dependencies = dependencies(&builder);
+        let external = find(&dependencies, vk::VK_SUBPASS_EXTERNAL, 0).unwrap();
+        assert_eq!(external.src_stage_mask,
+                   vk::PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT |
+                   vk::PIPELINE_STAGE_EARLY_FRAGMENT_TESTS_BIT |
+                   vk::PIPELINE_STAGE_LATE_FRAGMENT_TESTS_BIT);
+        assert_eq!(external.src_access_mask,
+                   vk::ACCESS_COLOR_ATTACHMENT_WRITE_BIT |
+                   vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_WRITE_BIT);
+        assert_eq!(external.dst_access_mask,
+                   vk::ACCESS_COLOR_ATTACHMENT_READ_BIT | vk::ACCESS_COLOR_ATTACHMENT_WRITE_BIT |
+                   vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_READ_BIT |
+                   vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_WRITE_BIT);
+        assert_eq!(external.dependency_flags, vk::DependencyFlags::empty());
+        // Presentation waits on a semaphore, the depth attachment is used after the pass.
+        let outgoing = find(&dependencies, 0, vk::VK_SUBPASS_EXTERNAL).unwrap();
+        assert_eq!(outgoing.src_access_mask, vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_WRITE_BIT);
+        assert_eq!(dependencies.len(), 2);
+    }
+
+    #[test]
+    fn input_attachments_depend_on_the_subpass_that_wrote_them() {
+        let builder = RenderPassBuilder::new()
+            .attachment("albedo", attachment(vk::ImageLayout::ColorAttachmentOptimal))
+            .attachment("output", attachment(vk::ImageLayout::PresentSrcKhr))
+            .subpass(Subpass::new().color("albedo"))
+            .subpass(Subpass::new().input("albedo").color("output"));
+        let dependencies = dependencies(&builder);
+        let between = find(&dependencies, 0, 1).unwrap();
+        assert_eq!(between.src_stage_mask, vk::PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT);
+        assert_eq!(between.src_access_mask, vk::ACCESS_COLOR_ATTACHMENT_WRITE_BIT);
+        assert_eq!(between.dst_stage_mask, vk::PIPELINE_STAGE_FRAGMENT_SHADER_BIT);
+        assert_eq!(between.dst_access_mask, vk::ACCESS_INPUT_ATTACHMENT_READ_BIT);
+        assert_eq!(between.dependency_flags, vk::DEPENDENCY_BY_REGION_BIT);
+        assert!(find(&dependencies, vk::VK_SUBPASS_EXTERNAL, 1).is_some());
+    }
+
+    #[test]
+    fn read_only_uses_in_the_same_layout_do_not_depend_on_each_other() {
+        let builder = RenderPassBuilder::new()
+            .attachment("depth", attachment(vk::ImageLayout::DepthStencilReadOnlyOptimal))
+            .subpass(Subpass::new().depth_read_only("depth"))
+            .subpass(Subpass::new().depth_read_only("depth"));
+        let dependencies = dependencies(&builder);
+        assert!(find(&dependencies, 0, 1).is_none());
+        assert_eq!(dependencies.len(), 1);
+    }
+
+    #[test]
+    fn sampled_attachments_are_made_visible_to_later_shaders() {
+        let builder = RenderPassBuilder::new()
+            .attachment("shadow", attachment(vk::ImageLayout::ShaderReadOnlyOptimal))
+            .subpass(Subpass::new().color("shadow"));
+        let dependencies = dependencies(&builder);
+        let outgoing = find(&dependencies, 0, vk::VK_SUBPASS_EXTERNAL).unwrap();
+        assert_eq!(outgoing.src_stage_mask, vk::PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT);
+        assert_eq!(outgoing.src_access_mask, vk::ACCESS_COLOR_ATTACHMENT_WRITE_BIT);
+        assert_eq!(outgoing.dst_stage_mask,
+                   vk::PIPELINE_STAGE_VERTEX_SHADER_BIT | vk::PIPELINE_STAGE_FRAGMENT_SHADER_BIT |
+                   vk::PIPELINE_STAGE_COMPUTE_SHADER_BIT);
+        assert_eq!(outgoing.dst_access_mask, vk::ACCESS_SHADER_READ_BIT);
+        assert_eq!(outgoing.dependency_flags, vk::DependencyFlags::empty());
+    }
+}
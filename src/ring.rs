@@ -0,0 +1,215 @@
+use prelude::*;
+use std::cmp;
+use std::mem;
+use std::ptr;
+use std::slice;
+use vk;
+use device::Device;
+use memory::{MapError, MappedMemory, MemoryUsage};
+
+#[derive(Debug)]
+pub enum RingError {
+    /// The current frame has no room left for the allocation.
+    OutOfSpace {
+        size: vk::DeviceSize,
+        available: vk::DeviceSize,
+    },
+    NoCompatibleMemoryType,
+    MapError(MapError),
+    VkError(vk::Result),
+}
+
+/// Linearly allocates data that is rewritten every frame, such as uniforms, out of a
+/// persistently mapped buffer. The buffer is split into one region per frame in flight, a
+/// region is reused once the fence passed to `finish_frame` for it has signaled.
+///
+/// The returned offsets are meant as dynamic offsets for `cmd_bind_descriptor_sets`, with the
+/// descriptor itself pointing at offset 0 of `buffer()`. The ring has to outlive the frames
+/// that use it, wait for the device to be idle before dropping it.
+pub struct UniformRing<'a> {
+    device: &'a Device<'a>,
+    buffer: vk::Buffer,
+    memory: vk::DeviceMemory,
+    mapping: Option<MappedMemory<'a, u8>>,
+    coherent: bool,
+    frame_size: vk::DeviceSize,
+    frame_fences: Vec<Option<vk::Fence>>,
+    frame_index: usize,
+    head: vk::DeviceSize,
+}
+
+impl<'a> UniformRing<'a> {
+    /// Creates a buffer with room for `frame_size` bytes in each of `frame_count` frames.
+    /// `usage` is usually `BUFFER_USAGE_UNIFORM_BUFFER_BIT`, `BUFFER_USAGE_STORAGE_BUFFER_BIT`
+    /// or both. Panics if `frame_count` is 0.
+    pub fn new(device: &'a Device<'a>,
+               memory_properties: &vk::PhysicalDeviceMemoryProperties,
+               usage: vk::BufferUsageFlags,
+               frame_size: vk::DeviceSize,
+               frame_count: usize)
+               -> Result<UniformRing<'a>, RingError> {
+        assert!(frame_count > 0, "a UniformRing needs at least one frame");
+        // Both alignments are powers of two, so the larger one satisfies either.
+        let alignment = {
+            let limits = device.limits();
+            cmp::max(cmp::max(limits.min_uniform_buffer_offset_alignment,
+                              limits.min_storage_buffer_offset_alignment),
+                     1)
+        };
+        let frame_size = align_up(frame_size, alignment);
+        let size = frame_size * frame_count as vk::DeviceSize;
+        let buffer_info = vk::BufferCreateInfo {
+            s_type: vk::StructureType::BufferCreateInfo,
+            p_next: ptr::null(),
+            flags: vk::BufferCreateFlags::empty(),
+            size,
+            usage,
+            sharing_mode: vk::SharingMode::Exclusive,
+            queue_family_index_count: 0,
+            p_queue_family_indices: ptr::null(),
+        };
+        let buffer = device.create_buffer(&buffer_info).map_err(RingError::VkError)?;
+        let requirements = device.get_buffer_memory_requirements(buffer);
+        let memory_type = match memory_properties
+            .find_memory_type_for_usage(requirements.memory_type_bits, MemoryUsage::CpuToGpu) {
+            Some(memory_type) => memory_type,
+            None => {
+                device.destroy_buffer(buffer);
+                return Err(RingError::NoCompatibleMemoryType);
+            }
+        };
+        let allocate_info = vk::MemoryAllocateInfo {
+            s_type: vk::StructureType::MemoryAllocateInfo,
+            p_next: ptr::null(),
+            allocation_size: requirements.size,
+            memory_type_index: memory_type.index,
+        };
+        let memory = match device.allocate_memory(&allocate_info) {
+            Ok(memory) => memory,
+            Err(err) => {
+                device.destroy_buffer(buffer);
+                return Err(RingError::VkError(err));
+            }
+        };
+        let mapping = device.bind_buffer_memory(buffer, memory, 0)
+            .map_err(RingError::VkError)
+            .and_then(|_| {
+                device.map_memory::<u8>(memory, 0, size, vk::MemoryMapFlags::empty())
+                    .map_err(RingError::MapError)
+            });
+        let mapping = match mapping {
+            Ok(mapping) => mapping,
+            Err(err) => {
+                device.destroy_buffer(buffer);
+                device.free_memory(memory);
+                return Err(err);
+            }
+        };
+        Ok(UniformRing {
+            device,
+            buffer,
+            memory,
+            mapping: Some(mapping),
+            coherent: memory_type.property_flags.subset(vk::MEMORY_PROPERTY_HOST_COHERENT_BIT),
+            frame_size,
+            frame_fences: vec![None; frame_count],
+            frame_index: 0,
+            head: 0,
+        })
+    }
+
+    pub fn buffer(&self) -> vk::Buffer {
+        self.buffer
+    }
+
+    /// The size of each frame's region, rounded up to the offset alignment.
+    pub fn frame_size(&self) -> vk::DeviceSize {
+        self.frame_size
+    }
+
+    pub fn frame_index(&self) -> usize {
+        self.frame_index
+    }
+
+    /// Waits until the region of the current frame is no longer used by the device and starts
+    /// allocating from its beginning. Has to be called before the caller resets the fence that
+    /// was passed to `finish_frame` for this region.
+    pub fn begin_frame(&mut self) -> VkResult<()> {
+        if let Some(fence) = self.frame_fences[self.frame_index] {
+            self.device.wait_for_fences(&[fence], true, u64::MAX)?;
+        }
+        self.frame_fences[self.frame_index] = None;
+        self.head = 0;
+        Ok(())
+    }
+
+    /// Makes the writes of this frame visible to the device and moves on to the next region.
+    /// `fence` has to be signaled by the submission that reads this frame's data.
+    pub fn finish_frame(&mut self, fence: vk::Fence) -> VkResult<()> {
+        if !self.coherent && self.head > 0 {
+            self.mapping.as_ref().unwrap().flush()?;
+        }
+        self.frame_fences[self.frame_index] = Some(fence);
+        self.frame_index = (self.frame_index + 1) % self.frame_fences.len();
+        self.head = 0;
+        Ok(())
+    }
+
+    /// Reserves `size` bytes in the current frame and returns their offset in `buffer()`
+    /// together with the mapped bytes.
+    pub fn allocate(&mut self,
+                    size: vk::DeviceSize,
+                    alignment: vk::DeviceSize)
+                    -> Result<(vk::Buffer, vk::uint32_t, &mut [u8]), RingError> {
+        let offset = align_up(self.head, cmp::max(alignment, 1));
+        if offset + size > self.frame_size {
+            return Err(RingError::OutOfSpace {
+                size,
+                available: self.frame_size - cmp::min(offset, self.frame_size),
+            });
+        }
+        self.head = offset + size;
+        let start = (self.frame_index as vk::DeviceSize * self.frame_size + offset) as usize;
+        let bytes = &mut self.mapping.as_mut().unwrap()[start..start + size as usize];
+        Ok((self.buffer, start as vk::uint32_t, bytes))
+    }
+
+    /// Copies `data` into the current frame at `min_uniform_buffer_offset_alignment`.
+    pub fn push_uniform<T: Copy>(&mut self,
+                                 data: &T)
+                                 -> Result<(vk::Buffer, vk::uint32_t), RingError> {
+        let alignment = self.device.limits().min_uniform_buffer_offset_alignment;
+        self.push(slice::from_ref(data), alignment)
+    }
+
+    /// Copies `data` into the current frame at `min_storage_buffer_offset_alignment`.
+    pub fn push_storage<T: Copy>(&mut self,
+                                 data: &[T])
+                                 -> Result<(vk::Buffer, vk::uint32_t), RingError> {
+        let alignment = self.device.limits().min_storage_buffer_offset_alignment;
+        self.push(data, alignment)
+    }
+
+    fn push<T: Copy>(&mut self,
+                     data: &[T],
+                     alignment: vk::DeviceSize)
+                     -> Result<(vk::Buffer, vk::uint32_t), RingError> {
+        let size = mem::size_of_val(data);
+        let bytes = unsafe { slice::from_raw_parts(data.as_ptr() as *const u8, size) };
+        let (buffer, offset, mapped) = self.allocate(size as vk::DeviceSize, alignment)?;
+        mapped.copy_from_slice(bytes);
+        Ok((buffer, offset))
+    }
+}
+
+impl<'a> Drop for UniformRing<'a> {
+    fn drop(&mut self) {
+        self.mapping.take();
+        self.device.destroy_buffer(self.buffer);
+        self.device.free_memory(self.memory);
+    }
+}
+
+fn align_up(offset: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    offset.div_ceil(alignment) * alignment
+}
@@ -0,0 +1,476 @@
+use prelude::*;
+use std::cmp;
+use std::collections::HashMap;
+use std::ptr;
+use vk;
+use device::Device;
+
+#[derive(Debug)]
+pub enum SparseError {
+    /// Every page of the backing memory is already bound.
+    OutOfPages,
+    /// The image was not registered with `register_image`.
+    UnknownImage(vk::Image),
+    /// The tile starts outside of the extent of its mip level.
+    TileOutOfRange(ImageTile),
+    /// The mip level of the tile is part of the mip tail, which is bound with opaque pages.
+    InMipTail(ImageTile),
+}
+
+/// A tile of a sparse image, `x`, `y` and `z` are measured in units of the image granularity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImageTile {
+    pub aspect_mask: vk::ImageAspectFlags,
+    pub mip_level: vk::uint32_t,
+    pub array_layer: vk::uint32_t,
+    pub x: vk::uint32_t,
+    pub y: vk::uint32_t,
+    pub z: vk::uint32_t,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum OpaqueResource {
+    Buffer(vk::Buffer),
+    Image(vk::Image),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ImageInfo {
+    extent: (vk::uint32_t, vk::uint32_t, vk::uint32_t),
+    granularity: (vk::uint32_t, vk::uint32_t, vk::uint32_t),
+    mip_tail_first_lod: vk::uint32_t,
+}
+
+/// Tracks which pages of sparse buffers and images are resident in a single `vk::DeviceMemory`
+/// and records every commit and eviction until they are submitted with one
+/// `queue_bind_sparse` call in `flush`.
+///
+/// The memory is split into pages of `page_size` bytes, which has to be a multiple of the
+/// sparse block size (`MemoryRequirements::alignment`) of every resource bound to it.
+/// Evicting a page that is still accessed by in flight work is undefined behavior, it is up
+/// to the caller to only evict pages that are no longer in use.
+pub struct SparseResidency {
+    memory: vk::DeviceMemory,
+    page_size: vk::DeviceSize,
+    free_pages: Vec<vk::DeviceSize>,
+    images: HashMap<vk::Image, ImageInfo>,
+    opaque_pages: HashMap<(OpaqueResource, vk::DeviceSize), vk::DeviceSize>,
+    image_tiles: HashMap<(vk::Image, ImageTile), vk::DeviceSize>,
+    pending_opaque: Vec<(OpaqueResource, vk::SparseMemoryBind)>,
+    pending_image: Vec<(vk::Image, vk::SparseImageMemoryBind)>,
+}
+
+impl SparseResidency {
+    pub fn new(memory: vk::DeviceMemory,
+               page_size: vk::DeviceSize,
+               page_count: vk::DeviceSize)
+               -> SparseResidency {
+        SparseResidency {
+            memory,
+            page_size,
+            free_pages: (0..page_count).rev().map(|page| page * page_size).collect(),
+            images: HashMap::new(),
+            opaque_pages: HashMap::new(),
+            image_tiles: HashMap::new(),
+            pending_opaque: Vec::new(),
+            pending_image: Vec::new(),
+        }
+    }
+
+    pub fn memory(&self) -> vk::DeviceMemory {
+        self.memory
+    }
+
+    pub fn page_size(&self) -> vk::DeviceSize {
+        self.page_size
+    }
+
+    pub fn free_page_count(&self) -> usize {
+        self.free_pages.len()
+    }
+
+    /// Returns true if there are commits or evictions that have not been flushed yet.
+    pub fn has_pending(&self) -> bool {
+        !self.pending_opaque.is_empty() || !self.pending_image.is_empty()
+    }
+
+    /// Registers a sparse residency image so that tiles can be committed. The granularity
+    /// and the first mip level of the mip tail can be found in the
+    /// `SparseImageMemoryRequirements` returned from
+    /// `Device::get_image_sparse_memory_requirements`.
+    pub fn register_image(&mut self,
+                          image: vk::Image,
+                          extent: &vk::Extent3D,
+                          granularity: &vk::Extent3D,
+                          mip_tail_first_lod: vk::uint32_t) {
+        self.images.insert(image,
+                           ImageInfo {
+                               extent: (extent.width, extent.height, extent.depth),
+                               granularity: (granularity.width,
+                                             granularity.height,
+                                             granularity.depth),
+                               mip_tail_first_lod,
+                           });
+    }
+
+    /// Evicts every page of `image` and forgets about it.
+    pub fn unregister_image(&mut self, image: vk::Image) {
+        let tiles: Vec<ImageTile> = self.image_tiles
+            .keys()
+            .filter(|&&(tile_image, _)| tile_image == image)
+            .map(|&(_, tile)| tile)
+            .collect();
+        for tile in tiles {
+            let _ = self.evict_image_tile(image, tile);
+        }
+        let pages: Vec<vk::DeviceSize> = self.opaque_pages
+            .keys()
+            .filter(|&&(resource, _)| resource == OpaqueResource::Image(image))
+            .map(|&(_, page)| page)
+            .collect();
+        for page in pages {
+            self.evict_image_opaque_page(image, page);
+        }
+        self.images.remove(&image);
+    }
+
+    pub fn is_buffer_page_resident(&self, buffer: vk::Buffer, page: vk::DeviceSize) -> bool {
+        self.opaque_pages.contains_key(&(OpaqueResource::Buffer(buffer), page))
+    }
+
+    pub fn is_image_tile_resident(&self, image: vk::Image, tile: ImageTile) -> bool {
+        self.image_tiles.contains_key(&(image, tile))
+    }
+
+    /// Binds a page of memory to the `page`th `page_size` sized range of `buffer`.
+    pub fn commit_buffer_page(&mut self,
+                              buffer: vk::Buffer,
+                              page: vk::DeviceSize)
+                              -> Result<(), SparseError> {
+        self.commit_opaque(OpaqueResource::Buffer(buffer), page)
+    }
+
+    pub fn evict_buffer_page(&mut self, buffer: vk::Buffer, page: vk::DeviceSize) {
+        self.evict_opaque(OpaqueResource::Buffer(buffer), page)
+    }
+
+    /// Opaque image pages are used for the mip tail and metadata of a sparse image. Pages are
+    /// counted from the start of the image, the mip tail begins at
+    /// `SparseImageMemoryRequirements::image_mip_tail_offset`.
+    pub fn commit_image_opaque_page(&mut self,
+                                    image: vk::Image,
+                                    page: vk::DeviceSize)
+                                    -> Result<(), SparseError> {
+        self.commit_opaque(OpaqueResource::Image(image), page)
+    }
+
+    pub fn evict_image_opaque_page(&mut self, image: vk::Image, page: vk::DeviceSize) {
+        self.evict_opaque(OpaqueResource::Image(image), page)
+    }
+
+    /// Fails without using up a page if the tile is outside of its mip level or in the mip
+    /// tail.
+    pub fn commit_image_tile(&mut self,
+                             image: vk::Image,
+                             tile: ImageTile)
+                             -> Result<(), SparseError> {
+        if self.image_tiles.contains_key(&(image, tile)) {
+            return Ok(());
+        }
+        let bind = self.image_bind(image, tile)?;
+        let memory_offset = self.free_pages.pop().ok_or(SparseError::OutOfPages)?;
+        self.image_tiles.insert((image, tile), memory_offset);
+        self.pending_image.push((image,
+                                 vk::SparseImageMemoryBind {
+                                     memory: self.memory,
+                                     memory_offset,
+                                     ..bind
+                                 }));
+        Ok(())
+    }
+
+    pub fn evict_image_tile(&mut self,
+                            image: vk::Image,
+                            tile: ImageTile)
+                            -> Result<(), SparseError> {
+        if let Some(memory_offset) = self.image_tiles.remove(&(image, tile)) {
+            let bind = self.image_bind(image, tile)?;
+            self.free_pages.push(memory_offset);
+            self.pending_image.push((image, bind));
+        }
+        Ok(())
+    }
+
+    /// Submits every pending commit and eviction with a single `queue_bind_sparse` call.
+    /// `queue` has to support `QUEUE_SPARSE_BINDING_BIT`. Pending binds are kept if the
+    /// submission fails.
+    pub fn flush(&mut self,
+                 device: &Device,
+                 queue: vk::Queue,
+                 wait_semaphores: &[vk::Semaphore],
+                 signal_semaphores: &[vk::Semaphore],
+                 fence: vk::Fence)
+                 -> VkResult<()> {
+        let mut buffer_binds: Vec<(vk::Buffer, Vec<vk::SparseMemoryBind>)> = Vec::new();
+        let mut opaque_binds: Vec<(vk::Image, Vec<vk::SparseMemoryBind>)> = Vec::new();
+        for &(resource, ref bind) in &self.pending_opaque {
+            match resource {
+                OpaqueResource::Buffer(buffer) => push_grouped(&mut buffer_binds, buffer, bind),
+                OpaqueResource::Image(image) => push_grouped(&mut opaque_binds, image, bind),
+            }
+        }
+        let mut image_binds: Vec<(vk::Image, Vec<vk::SparseImageMemoryBind>)> = Vec::new();
+        for &(image, ref bind) in &self.pending_image {
+            push_grouped(&mut image_binds, image, bind);
+        }
+        let buffer_infos: Vec<vk::SparseBufferMemoryBindInfo> = buffer_binds.iter()
+            .map(|&(buffer, ref binds)| {
+                vk::SparseBufferMemoryBindInfo {
+                    buffer,
+                    bind_count: binds.len() as vk::uint32_t,
+                    p_binds: binds.as_ptr(),
+                }
+            })
+            .collect();
+        let opaque_infos: Vec<vk::SparseImageOpaqueMemoryBindInfo> = opaque_binds.iter()
+            .map(|&(image, ref binds)| {
+                vk::SparseImageOpaqueMemoryBindInfo {
+                    image,
+                    bind_count: binds.len() as vk::uint32_t,
+                    p_binds: binds.as_ptr(),
+                }
+            })
+            .collect();
+        let image_infos: Vec<vk::SparseImageMemoryBindInfo> = image_binds.iter()
+            .map(|&(image, ref binds)| {
+                vk::SparseImageMemoryBindInfo {
+                    image,
+                    bind_count: binds.len() as vk::uint32_t,
+                    p_binds: binds.as_ptr(),
+                }
+            })
+            .collect();
+        let bind_info = vk::BindSparseInfo {
+            s_type: vk::StructureType::BindSparseInfo,
+            p_next: ptr::null(),
+            wait_semaphore_count: wait_semaphores.len() as vk::uint32_t,
+            p_wait_semaphores: wait_semaphores.as_ptr(),
+            buffer_bind_count: buffer_infos.len() as vk::uint32_t,
+            p_buffer_binds: buffer_infos.as_ptr(),
+            image_opaque_bind_count: opaque_infos.len() as vk::uint32_t,
+            p_image_opaque_binds: opaque_infos.as_ptr(),
+            image_bind_count: image_infos.len() as vk::uint32_t,
+            p_image_binds: image_infos.as_ptr(),
+            signal_semaphore_count: signal_semaphores.len() as vk::uint32_t,
+            p_signal_semaphores: signal_semaphores.as_ptr(),
+        };
+        device.queue_bind_sparse(queue, &[bind_info], fence)?;
+        self.pending_opaque.clear();
+        self.pending_image.clear();
+        Ok(())
+    }
+
+    fn commit_opaque(&mut self,
+                     resource: OpaqueResource,
+                     page: vk::DeviceSize)
+                     -> Result<(), SparseError> {
+        if self.opaque_pages.contains_key(&(resource, page)) {
+            return Ok(());
+        }
+        let memory_offset = self.free_pages.pop().ok_or(SparseError::OutOfPages)?;
+        self.opaque_pages.insert((resource, page), memory_offset);
+        let bind = vk::SparseMemoryBind {
+            resource_offset: page * self.page_size,
+            size: self.page_size,
+            memory: self.memory,
+            memory_offset,
+            flags: vk::SparseMemoryBindFlags::empty(),
+        };
+        self.pending_opaque.push((resource, bind));
+        Ok(())
+    }
+
+    fn evict_opaque(&mut self, resource: OpaqueResource, page: vk::DeviceSize) {
+        if let Some(memory_offset) = self.opaque_pages.remove(&(resource, page)) {
+            self.free_pages.push(memory_offset);
+            let bind = vk::SparseMemoryBind {
+                resource_offset: page * self.page_size,
+                size: self.page_size,
+                memory: vk::DeviceMemory::null(),
+                memory_offset: 0,
+                flags: vk::SparseMemoryBindFlags::empty(),
+            };
+            self.pending_opaque.push((resource, bind));
+        }
+    }
+
+    /// Describes the region covered by `tile`, without any memory bound to it. Tiles at the
+    /// edge of a mip level are clamped to the extent of that level.
+    fn image_bind(&self,
+                  image: vk::Image,
+                  tile: ImageTile)
+                  -> Result<vk::SparseImageMemoryBind, SparseError> {
+        let info = *self.images.get(&image).ok_or(SparseError::UnknownImage(image))?;
+        if tile.mip_level >= info.mip_tail_first_lod {
+            return Err(SparseError::InMipTail(tile));
+        }
+        let (width, height, depth) = info.extent;
+        let (granularity_x, granularity_y, granularity_z) = info.granularity;
+        // Returns the offset and the clamped extent of the tile along one axis.
+        let axis = |tile_index: vk::uint32_t, granularity: vk::uint32_t, extent: vk::uint32_t| {
+            let mip_extent = cmp::max(extent >> tile.mip_level, 1);
+            match tile_index.checked_mul(granularity) {
+                Some(offset) if offset < mip_extent => {
+                    Ok((offset, cmp::min(granularity, mip_extent - offset)))
+                }
+                _ => Err(SparseError::TileOutOfRange(tile)),
+            }
+        };
+        let (x, width) = axis(tile.x, granularity_x, width)?;
+        let (y, height) = axis(tile.y, granularity_y, height)?;
+        let (z, depth) = axis(tile.z, granularity_z, depth)?;
+        Ok(vk::SparseImageMemoryBind {
+            subresource: vk::ImageSubresource {
+                aspect_mask: tile.aspect_mask,
+                mip_level: tile.mip_level,
+                array_layer: tile.array_layer,
+            },
+            offset: vk::Offset3D {
+                x: x as vk::int32_t,
+                y: y as vk::int32_t,
+                z: z as vk::int32_t,
+            },
+            extent: vk::Extent3D {
+                width,
+                height,
+                depth,
+            },
+            memory: vk::DeviceMemory::null(),
+            memory_offset: 0,
+            flags: vk::SparseMemoryBindFlags::empty(),
+        })
+    }
+}
+
+fn push_grouped<K, T>(groups: &mut Vec<(K, Vec<T>)>, key: K, value: &T)
+    where K: PartialEq + Copy,
+          T: Clone
+{
+    if let Some(&mut (_, ref mut values)) = groups.iter_mut().find(|&&mut (k, _)| k == key) {
+        values.push(value.clone());
+        return;
+    }
+    groups.push((key, vec![value.clone()]));
+}
+
+#[cfg(test)]
+mod tests {
+    use vk;
+    use super::{ImageTile, SparseError, SparseResidency};
+
+    fn residency(page_count: vk::DeviceSize) -> SparseResidency {
+        let mut residency = SparseResidency::new(vk::DeviceMemory::null(), 65536, page_count);
+        residency.register_image(vk::Image::null(),
+                                 &vk::Extent3D {
+                                     width: 200,
+                                     height: 100,
+                                     depth: 1,
+                                 },
+                                 &vk::Extent3D {
+                                     width: 64,
+                                     height: 64,
+                                     depth: 1,
+                                 },
+                                 2);
+        residency
+    }
+
+    fn tile(mip_level: vk::uint32_t, x: vk::uint32_t, y: vk::uint32_t) -> ImageTile {
+        ImageTile {
+            aspect_mask: vk::IMAGE_ASPECT_COLOR_BIT,
+            mip_level,
+            array_layer: 0,
+            x,
+            y,
+            z: 0,
+        }
+    }
+
+    #[test]
+    fn edge_tiles_are_clamped_to_the_mip_extent() {
+        let residency = residency(0);
+        let bind = residency.image_bind(vk::Image::null(), tile(0, 3, 1)).unwrap();
+        assert_eq!((bind.offset.x, bind.offset.y, bind.offset.z), (192, 64, 0));
+        assert_eq!((bind.extent.width, bind.extent.height, bind.extent.depth), (8, 36, 1));
+        // Mip level 1 is 100x50.
+        let bind = residency.image_bind(vk::Image::null(), tile(1, 1, 0)).unwrap();
+        assert_eq!((bind.offset.x, bind.offset.y), (64, 0));
+        assert_eq!((bind.extent.width, bind.extent.height), (36, 50));
+    }
+
+    #[test]
+    fn invalid_tiles_are_rejected_without_using_a_page() {
+        let mut residency = residency(1);
+        match residency.commit_image_tile(vk::Image::null(), tile(0, 4, 0)) {
+            Err(SparseError::TileOutOfRange(_)) => {}
+            other => panic!("expected TileOutOfRange, got {:?}", other),
+        }
+        match residency.commit_image_tile(vk::Image::null(), tile(1, 0, 1)) {
+            Err(SparseError::TileOutOfRange(_)) => {}
+            other => panic!("expected TileOutOfRange, got {:?}", other),
+        }
+        match residency.commit_image_tile(vk::Image::null(), tile(0, 0x0400_0000, 0)) {
+            Err(SparseError::TileOutOfRange(_)) => {}
+            other => panic!("expected TileOutOfRange, got {:?}", other),
+        }
+        match residency.commit_image_tile(vk::Image::null(), tile(2, 0, 0)) {
+            Err(SparseError::InMipTail(_)) => {}
+            other => panic!("expected InMipTail, got {:?}", other),
+        }
+        assert_eq!(residency.free_page_count(), 1);
+        assert!(!residency.has_pending());
+    }
+
+    #[test]
+    fn commit_and_evict_track_pages() {
+        let mut residency = residency(2);
+        let image = vk::Image::null();
+        residency.commit_image_tile(image, tile(0, 0, 0)).unwrap();
+        // Committing a resident tile again is a no-op.
+        residency.commit_image_tile(image, tile(0, 0, 0)).unwrap();
+        residency.commit_image_tile(image, tile(0, 1, 0)).unwrap();
+        assert_eq!(residency.free_page_count(), 0);
+        assert_eq!(residency.pending_image.len(), 2);
+        match residency.commit_image_tile(image, tile(0, 2, 0)) {
+            Err(SparseError::OutOfPages) => {}
+            other => panic!("expected OutOfPages, got {:?}", other),
+        }
+
+        let offset = residency.image_tiles[&(image, tile(0, 0, 0))];
+        residency.evict_image_tile(image, tile(0, 0, 0)).unwrap();
+        assert!(!residency.is_image_tile_resident(image, tile(0, 0, 0)));
+        assert_eq!(residency.free_page_count(), 1);
+        let eviction = residency.pending_image.last().unwrap().1.clone();
+        assert_eq!(eviction.memory, vk::DeviceMemory::null());
+        // Evicting a tile that is not resident queues nothing.
+        residency.evict_image_tile(image, tile(0, 0, 0)).unwrap();
+        assert_eq!(residency.pending_image.len(), 3);
+
+        residency.commit_image_tile(image, tile(0, 2, 0)).unwrap();
+        assert_eq!(residency.image_tiles[&(image, tile(0, 2, 0))], offset);
+    }
+
+    #[test]
+    fn buffer_pages_are_recycled() {
+        let mut residency = residency(1);
+        let buffer = vk::Buffer::null();
+        residency.commit_buffer_page(buffer, 3).unwrap();
+        assert!(residency.is_buffer_page_resident(buffer, 3));
+        assert_eq!(residency.pending_opaque[0].1.resource_offset, 3 * 65536);
+        residency.evict_buffer_page(buffer, 3);
+        assert!(!residency.is_buffer_page_resident(buffer, 3));
+        residency.commit_buffer_page(buffer, 5).unwrap();
+        assert_eq!(residency.free_page_count(), 0);
+        assert_eq!(residency.pending_opaque.len(), 3);
+    }
+}
@@ -0,0 +1,423 @@
+use prelude::*;
+use std::cmp;
+use std::fmt;
+use std::ptr;
+use vk;
+use instance::Instance;
+
+#[derive(Debug)]
+pub enum SurfaceError {
+    /// The surface reported no formats at all.
+    NoSurfaceFormats,
+    /// The surface doesn't support every requested image usage.
+    UnsupportedUsage {
+        requested: vk::ImageUsageFlags,
+        supported: vk::ImageUsageFlags,
+    },
+}
+
+/// What the surface supports, as reported by the instance.
+#[derive(Debug, Clone)]
+pub struct SurfaceSupport {
+    pub capabilities: vk::SurfaceCapabilitiesKHR,
+    pub formats: Vec<vk::SurfaceFormatKHR>,
+    pub present_modes: Vec<vk::PresentModeKHR>,
+}
+
+impl SurfaceSupport {
+    pub fn query(instance: &Instance,
+                 physical_device: vk::PhysicalDevice,
+                 surface: vk::SurfaceKHR)
+                 -> VkResult<SurfaceSupport> {
+        Ok(SurfaceSupport {
+            capabilities:
+                instance.get_physical_device_surface_capabilities_khr(physical_device, surface)?,
+            formats: instance.get_physical_device_surface_formats_khr(physical_device, surface)?,
+            present_modes:
+                instance.get_physical_device_surface_present_modes_khr(physical_device, surface)?,
+        })
+    }
+}
+
+/// What the application would like, each list ordered from most to least preferred.
+#[derive(Debug, Clone)]
+pub struct SurfacePreferences {
+    pub formats: Vec<vk::SurfaceFormatKHR>,
+    pub present_modes: Vec<vk::PresentModeKHR>,
+    pub image_count: vk::uint32_t,
+    /// Has to be supported by the surface, `IMAGE_USAGE_COLOR_ATTACHMENT_BIT` always is.
+    pub image_usage: vk::ImageUsageFlags,
+}
+
+impl Default for SurfacePreferences {
+    /// sRGB or UNORM BGRA8, mailbox over FIFO and triple buffering.
+    fn default() -> SurfacePreferences {
+        SurfacePreferences {
+            formats: vec![vk::SurfaceFormatKHR {
+                              format: vk::Format::B8g8r8a8Srgb,
+                              color_space: vk::ColorSpaceKHR::SrgbNonlinear,
+                          },
+                          vk::SurfaceFormatKHR {
+                              format: vk::Format::B8g8r8a8Unorm,
+                              color_space: vk::ColorSpaceKHR::SrgbNonlinear,
+                          }],
+            present_modes: vec![vk::PresentModeKHR::Mailbox, vk::PresentModeKHR::Fifo],
+            image_count: 3,
+            image_usage: vk::IMAGE_USAGE_COLOR_ATTACHMENT_BIT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatReason {
+    /// The surface reported a single `Undefined` format, so any format can be used and the
+    /// first preference was taken.
+    AnyFormatAllowed,
+    /// The preference at this index is the first one the surface supports.
+    Preferred(usize),
+    /// None of the preferences is supported, the first format of the surface was taken.
+    FirstSupported,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PresentModeReason {
+    /// The preference at this index is the first one the surface supports.
+    Preferred(usize),
+    /// None of the preferences is supported, FIFO is the only mode every surface supports.
+    FifoFallback,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtentReason {
+    /// The surface dictates its size through `current_extent`.
+    CurrentExtent,
+    /// `current_extent` is `0xFFFFFFFF`, so the window size was used.
+    WindowExtent,
+    /// The window size was outside of `min_image_extent` and `max_image_extent`.
+    ClampedWindowExtent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageCountReason {
+    Requested,
+    /// The requested count was below `min_image_count`.
+    RaisedToMinimum,
+    /// The requested count was above `max_image_count`.
+    LoweredToMaximum,
+}
+
+impl fmt::Display for FormatReason {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FormatReason::AnyFormatAllowed => {
+                write!(fmt, "the surface allows any format, took the first preference")
+            }
+            FormatReason::Preferred(index) => {
+                write!(fmt, "preference {} is the first one the surface supports", index)
+            }
+            FormatReason::FirstSupported => {
+                write!(fmt, "no preference is supported, took the first supported format")
+            }
+        }
+    }
+}
+
+impl fmt::Display for PresentModeReason {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PresentModeReason::Preferred(index) => {
+                write!(fmt, "preference {} is the first one the surface supports", index)
+            }
+            PresentModeReason::FifoFallback => {
+                write!(fmt, "no preference is supported, FIFO is always available")
+            }
+        }
+    }
+}
+
+impl fmt::Display for ExtentReason {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExtentReason::CurrentExtent => write!(fmt, "the surface dictates its current extent"),
+            ExtentReason::WindowExtent => {
+                write!(fmt, "the surface lets the swapchain decide, took the window size")
+            }
+            ExtentReason::ClampedWindowExtent => {
+                write!(fmt, "the window size was clamped to the supported extents")
+            }
+        }
+    }
+}
+
+impl fmt::Display for ImageCountReason {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImageCountReason::Requested => write!(fmt, "the requested count is supported"),
+            ImageCountReason::RaisedToMinimum => {
+                write!(fmt, "raised to the minimum image count of the surface")
+            }
+            ImageCountReason::LoweredToMaximum => {
+                write!(fmt, "lowered to the maximum image count of the surface")
+            }
+        }
+    }
+}
+
+/// The outcome of matching `SurfacePreferences` against `SurfaceSupport`, with the reason
+/// behind every choice.
+#[derive(Debug, Clone)]
+pub struct SurfaceConfig {
+    pub format: vk::SurfaceFormatKHR,
+    pub format_reason: FormatReason,
+    pub present_mode: vk::PresentModeKHR,
+    pub present_mode_reason: PresentModeReason,
+    /// Can be zero sized, e.g. for a minimized window. A swapchain can't be created until the
+    /// extent is non zero again.
+    pub extent: vk::Extent2D,
+    pub extent_reason: ExtentReason,
+    pub image_count: vk::uint32_t,
+    pub image_count_reason: ImageCountReason,
+    pub image_usage: vk::ImageUsageFlags,
+    /// Identity if supported, otherwise the current transform.
+    pub pre_transform: vk::SurfaceTransformFlagsKHR,
+    /// Opaque if supported, otherwise the first supported mode.
+    pub composite_alpha: vk::CompositeAlphaFlagsKHR,
+}
+
+impl SurfaceConfig {
+    /// `window_extent` is only used if the surface lets the swapchain decide its size.
+    pub fn negotiate(support: &SurfaceSupport,
+                     preferences: &SurfacePreferences,
+                     window_extent: vk::Extent2D)
+                     -> Result<SurfaceConfig, SurfaceError> {
+        let capabilities = &support.capabilities;
+        if !capabilities.supported_usage_flags.subset(preferences.image_usage) {
+            return Err(SurfaceError::UnsupportedUsage {
+                requested: preferences.image_usage,
+                supported: capabilities.supported_usage_flags,
+            });
+        }
+        let (format, format_reason) = choose_format(&support.formats, &preferences.formats)?;
+        let (present_mode, present_mode_reason) =
+            match preferences.present_modes
+                .iter()
+                .position(|mode| support.present_modes.contains(mode)) {
+                Some(index) => {
+                    (preferences.present_modes[index], PresentModeReason::Preferred(index))
+                }
+                None => (vk::PresentModeKHR::Fifo, PresentModeReason::FifoFallback),
+            };
+        let (extent, extent_reason) = if capabilities.current_extent.width == u32::MAX {
+            let extent = vk::Extent2D {
+                width: clamp(window_extent.width,
+                             capabilities.min_image_extent.width,
+                             capabilities.max_image_extent.width),
+                height: clamp(window_extent.height,
+                              capabilities.min_image_extent.height,
+                              capabilities.max_image_extent.height),
+            };
+            let reason = if extent.width == window_extent.width &&
+                            extent.height == window_extent.height {
+                ExtentReason::WindowExtent
+            } else {
+                ExtentReason::ClampedWindowExtent
+            };
+            (extent, reason)
+        } else {
+            (capabilities.current_extent.clone(), ExtentReason::CurrentExtent)
+        };
+        // A maximum of 0 means that there is no limit.
+        let (image_count, image_count_reason) = if preferences.image_count <
+                                                   capabilities.min_image_count {
+            (capabilities.min_image_count, ImageCountReason::RaisedToMinimum)
+        } else if capabilities.max_image_count > 0 &&
+                  preferences.image_count > capabilities.max_image_count {
+            (capabilities.max_image_count, ImageCountReason::LoweredToMaximum)
+        } else {
+            (preferences.image_count, ImageCountReason::Requested)
+        };
+        let pre_transform = if capabilities.supported_transforms
+            .subset(vk::SURFACE_TRANSFORM_IDENTITY_BIT_KHR) {
+            vk::SURFACE_TRANSFORM_IDENTITY_BIT_KHR
+        } else {
+            capabilities.current_transform
+        };
+        let composite_alpha = [vk::COMPOSITE_ALPHA_OPAQUE_BIT_KHR,
+                               vk::COMPOSITE_ALPHA_PRE_MULTIPLIED_BIT_KHR,
+                               vk::COMPOSITE_ALPHA_POST_MULTIPLIED_BIT_KHR,
+                               vk::COMPOSITE_ALPHA_INHERIT_BIT_KHR]
+            .iter()
+            .cloned()
+            .find(|&alpha| capabilities.supported_composite_alpha.subset(alpha))
+            .unwrap_or(vk::COMPOSITE_ALPHA_OPAQUE_BIT_KHR);
+        Ok(SurfaceConfig {
+            format,
+            format_reason,
+            present_mode,
+            present_mode_reason,
+            extent,
+            extent_reason,
+            image_count,
+            image_count_reason,
+            image_usage: preferences.image_usage,
+            pre_transform,
+            composite_alpha,
+        })
+    }
+
+    /// A create info for an exclusively owned swapchain with a single layer, clipped
+    /// presentation and everything else taken from this config.
+    pub fn swapchain_create_info(&self,
+                                 surface: vk::SurfaceKHR,
+                                 old_swapchain: vk::SwapchainKHR)
+                                 -> vk::SwapchainCreateInfoKHR {
+        vk::SwapchainCreateInfoKHR {
+            s_type: vk::StructureType::SwapchainCreateInfoKhr,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            surface,
+            min_image_count: self.image_count,
+            image_format: self.format.format,
+            image_color_space: self.format.color_space,
+            image_extent: self.extent.clone(),
+            image_array_layers: 1,
+            image_usage: self.image_usage,
+            image_sharing_mode: vk::SharingMode::Exclusive,
+            queue_family_index_count: 0,
+            p_queue_family_indices: ptr::null(),
+            pre_transform: self.pre_transform,
+            composite_alpha: self.composite_alpha,
+            present_mode: self.present_mode,
+            clipped: 1,
+            old_swapchain,
+        }
+    }
+}
+
+impl fmt::Display for SurfaceConfig {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(fmt,
+                 "format {:?} {:?}: {}",
+                 self.format.format,
+                 self.format.color_space,
+                 self.format_reason)?;
+        writeln!(fmt,
+                 "present mode {:?}: {}",
+                 self.present_mode,
+                 self.present_mode_reason)?;
+        writeln!(fmt,
+                 "extent {}x{}: {}",
+                 self.extent.width,
+                 self.extent.height,
+                 self.extent_reason)?;
+        write!(fmt,
+               "image count {}: {}",
+               self.image_count,
+               self.image_count_reason)
+    }
+}
+
+fn choose_format(supported: &[vk::SurfaceFormatKHR],
+                 preferred: &[vk::SurfaceFormatKHR])
+                 -> Result<(vk::SurfaceFormatKHR, FormatReason), SurfaceError> {
+    if supported.len() == 1 && supported[0].format == vk::Format::Undefined {
+        let format = preferred.first().cloned().unwrap_or(vk::SurfaceFormatKHR {
+            format: vk::Format::B8g8r8a8Unorm,
+            color_space: supported[0].color_space,
+        });
+        return Ok((format, FormatReason::AnyFormatAllowed));
+    }
+    let position = preferred.iter().position(|preference| {
+        supported.iter().any(|format| {
+            format.format == preference.format && format.color_space == preference.color_space
+        })
+    });
+    match position {
+        Some(index) => Ok((preferred[index].clone(), FormatReason::Preferred(index))),
+        None => {
+            supported.first()
+                .map(|format| (format.clone(), FormatReason::FirstSupported))
+                .ok_or(SurfaceError::NoSurfaceFormats)
+        }
+    }
+}
+
+fn clamp(value: u32, min: u32, max: u32) -> u32 {
+    cmp::max(min, cmp::min(value, max))
+}
+
+#[cfg(test)]
+mod tests {
+    use vk;
+    use super::{ExtentReason, FormatReason, PresentModeReason, SurfaceConfig, SurfacePreferences,
+                SurfaceSupport};
+
+    fn extent(width: u32, height: u32) -> vk::Extent2D {
+        vk::Extent2D {
+            width,
+            height,
+        }
+    }
+
+    fn support(current_extent: vk::Extent2D) -> SurfaceSupport {
+        SurfaceSupport {
+            capabilities: vk::SurfaceCapabilitiesKHR {
+                min_image_count: 2,
+                max_image_count: 8,
+                current_extent,
+                min_image_extent: extent(16, 16),
+                max_image_extent: extent(1024, 1024),
+                max_image_array_layers: 1,
+                supported_transforms: vk::SURFACE_TRANSFORM_IDENTITY_BIT_KHR,
+                current_transform: vk::SURFACE_TRANSFORM_IDENTITY_BIT_KHR,
+                supported_composite_alpha: vk::COMPOSITE_ALPHA_OPAQUE_BIT_KHR,
+                supported_usage_flags: vk::IMAGE_USAGE_COLOR_ATTACHMENT_BIT,
+            },
+            formats: vec![vk::SurfaceFormatKHR {
+                              format: vk::Format::R8g8b8a8Unorm,
+                              color_space: vk::ColorSpaceKHR::SrgbNonlinear,
+                          }],
+            present_modes: vec![vk::PresentModeKHR::Fifo],
+        }
+    }
+
+    #[test]
+    fn unsupported_preferences_fall_back() {
+        let config = SurfaceConfig::negotiate(&support(extent(640, 480)),
+                                              &SurfacePreferences::default(),
+                                              extent(800, 600))
+            .unwrap();
+        assert_eq!(config.format.format, vk::Format::R8g8b8a8Unorm);
+        assert_eq!(config.format_reason, FormatReason::FirstSupported);
+        assert_eq!(config.present_mode, vk::PresentModeKHR::Fifo);
+        assert_eq!(config.present_mode_reason, PresentModeReason::Preferred(1));
+        assert_eq!((config.extent.width, config.extent.height), (640, 480));
+        assert_eq!(config.extent_reason, ExtentReason::CurrentExtent);
+    }
+
+    #[test]
+    fn missing_present_modes_fall_back_to_fifo() {
+        let preferences = SurfacePreferences {
+            present_modes: vec![vk::PresentModeKHR::Mailbox, vk::PresentModeKHR::Immediate],
+            ..SurfacePreferences::default()
+        };
+        let config = SurfaceConfig::negotiate(&support(extent(640, 480)),
+                                              &preferences,
+                                              extent(800, 600))
+            .unwrap();
+        assert_eq!(config.present_mode, vk::PresentModeKHR::Fifo);
+        assert_eq!(config.present_mode_reason, PresentModeReason::FifoFallback);
+    }
+
+    #[test]
+    fn window_extent_is_clamped_without_current_extent() {
+        let support = support(extent(u32::MAX, u32::MAX));
+        let preferences = SurfacePreferences::default();
+        let config = SurfaceConfig::negotiate(&support, &preferences, extent(800, 600)).unwrap();
+        assert_eq!((config.extent.width, config.extent.height), (800, 600));
+        assert_eq!(config.extent_reason, ExtentReason::WindowExtent);
+        let config = SurfaceConfig::negotiate(&support, &preferences, extent(2048, 8)).unwrap();
+        assert_eq!((config.extent.width, config.extent.height), (1024, 16));
+        assert_eq!(config.extent_reason, ExtentReason::ClampedWindowExtent);
+    }
+}
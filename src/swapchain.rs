@@ -0,0 +1,241 @@
+use prelude::*;
+use std::ptr;
+use vk;
+use device::Device;
+use instance::Instance;
+use surface::{SurfaceConfig, SurfaceError, SurfacePreferences, SurfaceSupport};
+
+#[derive(Debug)]
+pub enum SwapchainError {
+    SurfaceError(SurfaceError),
+    VkError(vk::Result),
+}
+
+impl From<vk::Result> for SwapchainError {
+    fn from(err: vk::Result) -> SwapchainError {
+        SwapchainError::VkError(err)
+    }
+}
+
+/// Owns a `vk::SwapchainKHR` together with views of its images, and recreates both when the
+/// surface changes.
+///
+/// Everything that depends on the images or the extent, such as framebuffers, has to be
+/// rebuilt whenever `generation` changes. Recreation waits for the device to be idle, so the
+/// old images are never in use when they are destroyed.
+pub struct Swapchain<'a> {
+    instance: &'a Instance<'a>,
+    device: &'a Device<'a>,
+    physical_device: vk::PhysicalDevice,
+    surface: vk::SurfaceKHR,
+    handle: vk::SwapchainKHR,
+    preferences: SurfacePreferences,
+    config: SurfaceConfig,
+    window_extent: vk::Extent2D,
+    images: Vec<vk::Image>,
+    image_views: Vec<vk::ImageView>,
+    generation: u64,
+    out_of_date: bool,
+}
+
+impl<'a> Swapchain<'a> {
+    /// `window_extent` is used if the surface lets the swapchain decide its size.
+    pub fn new(instance: &'a Instance<'a>,
+               device: &'a Device<'a>,
+               physical_device: vk::PhysicalDevice,
+               surface: vk::SurfaceKHR,
+               window_extent: vk::Extent2D,
+               preferences: SurfacePreferences)
+               -> Result<Swapchain<'a>, SwapchainError> {
+        let support = SurfaceSupport::query(instance, physical_device, surface)?;
+        let config = SurfaceConfig::negotiate(&support, &preferences, window_extent.clone())
+            .map_err(SwapchainError::SurfaceError)?;
+        let mut swapchain = Swapchain {
+            instance,
+            device,
+            physical_device,
+            surface,
+            handle: vk::SwapchainKHR::null(),
+            preferences,
+            config,
+            window_extent,
+            images: Vec::new(),
+            image_views: Vec::new(),
+            generation: 0,
+            out_of_date: false,
+        };
+        swapchain.recreate()?;
+        Ok(swapchain)
+    }
+
+    pub fn handle(&self) -> vk::SwapchainKHR {
+        self.handle
+    }
+
+    /// The configuration the current swapchain was created with.
+    pub fn config(&self) -> &SurfaceConfig {
+        &self.config
+    }
+
+    pub fn format(&self) -> vk::SurfaceFormatKHR {
+        self.config.format.clone()
+    }
+
+    pub fn present_mode(&self) -> vk::PresentModeKHR {
+        self.config.present_mode
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        self.config.extent.clone()
+    }
+
+    pub fn images(&self) -> &[vk::Image] {
+        &self.images
+    }
+
+    pub fn image_views(&self) -> &[vk::ImageView] {
+        &self.image_views
+    }
+
+    /// Incremented every time the swapchain is recreated.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Recreates the swapchain with the new window size before the next image is acquired.
+    pub fn resize(&mut self, window_extent: vk::Extent2D) {
+        self.window_extent = window_extent;
+        self.out_of_date = true;
+    }
+
+    /// Acquires the next image, recreating the swapchain first if it is out of date. Returns
+    /// `None` if no image could be acquired because the swapchain had to be recreated or the
+    /// surface has a zero size, `semaphore` and `fence` are not signaled in that case and the
+    /// frame should be skipped.
+    pub fn acquire_next_image(&mut self,
+                              timeout: vk::uint64_t,
+                              semaphore: vk::Semaphore,
+                              fence: vk::Fence)
+                              -> Result<Option<vk::uint32_t>, SwapchainError> {
+        if self.out_of_date {
+            self.recreate()?;
+            if self.out_of_date {
+                return Ok(None);
+            }
+        }
+        match self.device.acquire_next_image_khr(self.handle, timeout, semaphore, fence) {
+            Ok((index, suboptimal)) => {
+                // The image is acquired, so it still has to be presented before recreating.
+                self.out_of_date |= suboptimal;
+                Ok(Some(index))
+            }
+            Err(vk::Result::ErrorOutOfDateKhr) => {
+                self.recreate()?;
+                Ok(None)
+            }
+            Err(err) => Err(SwapchainError::VkError(err)),
+        }
+    }
+
+    /// Presents `image_index` once `wait_semaphores` are signaled. A suboptimal or out of date
+    /// swapchain is not an error, it is recreated on the next `acquire_next_image`.
+    pub fn present(&mut self,
+                   queue: vk::Queue,
+                   image_index: vk::uint32_t,
+                   wait_semaphores: &[vk::Semaphore])
+                   -> VkResult<()> {
+        let present_info = vk::PresentInfoKHR {
+            s_type: vk::StructureType::PresentInfoKhr,
+            p_next: ptr::null(),
+            wait_semaphore_count: wait_semaphores.len() as vk::uint32_t,
+            p_wait_semaphores: wait_semaphores.as_ptr(),
+            swapchain_count: 1,
+            p_swapchains: &self.handle,
+            p_image_indices: &image_index,
+            p_results: ptr::null_mut(),
+        };
+        match self.device.queue_present_khr(queue, &present_info) {
+            Ok(suboptimal) => {
+                self.out_of_date |= suboptimal;
+                Ok(())
+            }
+            Err(vk::Result::ErrorOutOfDateKhr) => {
+                self.out_of_date = true;
+                Ok(())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Negotiates a new configuration from the current surface support and creates a new
+    /// swapchain, passing the old one as `old_swapchain`. Nothing happens while the surface has
+    /// a zero size, e.g. when the window is minimized, the swapchain stays out of date until
+    /// then.
+    pub fn recreate(&mut self) -> Result<(), SwapchainError> {
+        let support = SurfaceSupport::query(self.instance, self.physical_device, self.surface)?;
+        let config =
+            SurfaceConfig::negotiate(&support, &self.preferences, self.window_extent.clone())
+                .map_err(SwapchainError::SurfaceError)?;
+        if config.extent.width == 0 || config.extent.height == 0 {
+            self.out_of_date = true;
+            return Ok(());
+        }
+        let create_info = config.swapchain_create_info(self.surface, self.handle);
+        self.device.device_wait_idle()?;
+        let handle = self.device.create_swapchain_khr(&create_info)?;
+        self.destroy_swapchain();
+        self.handle = handle;
+        self.config = config;
+        self.images = self.device.get_swapchain_images_khr(handle)?;
+        for &image in &self.images {
+            let view = self.device
+                .create_image_view(&image_view_info(image, self.config.format.format))?;
+            self.image_views.push(view);
+        }
+        self.generation += 1;
+        self.out_of_date = false;
+        Ok(())
+    }
+
+    fn destroy_swapchain(&mut self) {
+        for view in self.image_views.drain(..) {
+            self.device.destroy_image_view(view);
+        }
+        self.images.clear();
+        if self.handle != vk::SwapchainKHR::null() {
+            self.device.destroy_swapchain_khr(self.handle);
+            self.handle = vk::SwapchainKHR::null();
+        }
+    }
+}
+
+impl<'a> Drop for Swapchain<'a> {
+    /// The surface is not destroyed, it belongs to the caller.
+    fn drop(&mut self) {
+        self.destroy_swapchain();
+    }
+}
+
+fn image_view_info(image: vk::Image, format: vk::Format) -> vk::ImageViewCreateInfo {
+    vk::ImageViewCreateInfo {
+        s_type: vk::StructureType::ImageViewCreateInfo,
+        p_next: ptr::null(),
+        flags: Default::default(),
+        view_type: vk::ImageViewType::Type2d,
+        format,
+        components: vk::ComponentMapping {
+            r: vk::ComponentSwizzle::Identity,
+            g: vk::ComponentSwizzle::Identity,
+            b: vk::ComponentSwizzle::Identity,
+            a: vk::ComponentSwizzle::Identity,
+        },
+        subresource_range: vk::ImageSubresourceRange {
+            aspect_mask: vk::IMAGE_ASPECT_COLOR_BIT,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        },
+        image,
+    }
+}
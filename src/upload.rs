@@ -0,0 +1,571 @@
+use prelude::*;
+use std::collections::VecDeque;
+use std::mem;
+use std::ptr;
+use std::slice;
+use vk;
+use device::Device;
+use memory::{MapError, MappedMemory, MemoryUsage};
+
+#[derive(Debug)]
+pub enum UploadError {
+    /// The upload is larger than the whole staging ring.
+    TooLarge {
+        size: vk::DeviceSize,
+        capacity: vk::DeviceSize,
+    },
+    NoCompatibleMemoryType,
+    MapError(MapError),
+    VkError(vk::Result),
+}
+
+impl From<vk::Result> for UploadError {
+    fn from(err: vk::Result) -> UploadError {
+        UploadError::VkError(err)
+    }
+}
+
+/// Identifies a batch of uploads submitted by `UploadManager::flush`. A token is complete once
+/// its batch, and therefore every batch submitted before it, has finished executing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UploadToken(u64);
+
+/// Prefers a queue family that only supports transfers, since those usually map to a DMA
+/// engine that runs in parallel to rendering. Falls back to any family that can transfer,
+/// graphics and compute queues always can.
+pub fn find_transfer_queue_family(queue_families: &[vk::QueueFamilyProperties]) -> Option<u32> {
+    let any_transfer = vk::QUEUE_TRANSFER_BIT | vk::QUEUE_GRAPHICS_BIT | vk::QUEUE_COMPUTE_BIT;
+    queue_families.iter()
+        .position(|family| {
+            family.queue_flags.subset(vk::QUEUE_TRANSFER_BIT) &&
+            !family.queue_flags.intersects(vk::QUEUE_GRAPHICS_BIT | vk::QUEUE_COMPUTE_BIT)
+        })
+        .or_else(|| {
+            queue_families.iter().position(|family| family.queue_flags.intersects(any_transfer))
+        })
+        .map(|index| index as u32)
+}
+
+struct ImageUpload {
+    image: vk::Image,
+    region: vk::BufferImageCopy,
+    old_layout: vk::ImageLayout,
+    new_layout: vk::ImageLayout,
+}
+
+struct Batch {
+    id: u64,
+    start: vk::DeviceSize,
+    fence: vk::Fence,
+    command_buffer: vk::CommandBuffer,
+}
+
+/// Copies data into device local buffers and images through a persistently mapped, host
+/// visible staging ring. Uploads are recorded until `flush` submits them as a single command
+/// buffer, and ring space is reused once the fence of its batch has signaled.
+///
+/// If the queue belongs to a different family than the queues that use the resources, the
+/// resources have to be created with `SharingMode::Concurrent`. Images end up in the layout
+/// requested in `upload_image`, see `flush` for how the uploads are made visible to other
+/// work.
+pub struct UploadManager<'a> {
+    device: &'a Device<'a>,
+    queue: vk::Queue,
+    command_pool: vk::CommandPool,
+    staging_buffer: vk::Buffer,
+    staging_memory: vk::DeviceMemory,
+    mapping: Option<MappedMemory<'a, u8>>,
+    coherent: bool,
+    image_alignment: vk::DeviceSize,
+    capacity: vk::DeviceSize,
+    // The ring is in use from `tail` up to `head`, wrapping around at `capacity`.
+    head: vk::DeviceSize,
+    tail: vk::DeviceSize,
+    pending_start: Option<vk::DeviceSize>,
+    buffer_copies: Vec<(vk::Buffer, vk::BufferCopy)>,
+    image_uploads: Vec<ImageUpload>,
+    in_flight: VecDeque<Batch>,
+    free_fences: Vec<vk::Fence>,
+    free_command_buffers: Vec<vk::CommandBuffer>,
+    next_id: u64,
+}
+
+impl<'a> UploadManager<'a> {
+    /// Creates a staging ring of `capacity` bytes. `queue` has to be from
+    /// `queue_family_index`, `find_transfer_queue_family` can be used to pick a family.
+    pub fn new(device: &'a Device<'a>,
+               memory_properties: &vk::PhysicalDeviceMemoryProperties,
+               queue: vk::Queue,
+               queue_family_index: u32,
+               capacity: vk::DeviceSize)
+               -> Result<UploadManager<'a>, UploadError> {
+        let buffer_info = vk::BufferCreateInfo {
+            s_type: vk::StructureType::BufferCreateInfo,
+            p_next: ptr::null(),
+            flags: vk::BufferCreateFlags::empty(),
+            size: capacity,
+            usage: vk::BUFFER_USAGE_TRANSFER_SRC_BIT,
+            sharing_mode: vk::SharingMode::Exclusive,
+            queue_family_index_count: 0,
+            p_queue_family_indices: ptr::null(),
+        };
+        let staging_buffer = device.create_buffer(&buffer_info)?;
+        let requirements = device.get_buffer_memory_requirements(staging_buffer);
+        let memory_type = match memory_properties
+            .find_memory_type_for_usage(requirements.memory_type_bits, MemoryUsage::CpuToGpu) {
+            Some(memory_type) => memory_type,
+            None => {
+                device.destroy_buffer(staging_buffer);
+                return Err(UploadError::NoCompatibleMemoryType);
+            }
+        };
+        let allocate_info = vk::MemoryAllocateInfo {
+            s_type: vk::StructureType::MemoryAllocateInfo,
+            p_next: ptr::null(),
+            allocation_size: requirements.size,
+            memory_type_index: memory_type.index,
+        };
+        let staging_memory = match device.allocate_memory(&allocate_info) {
+            Ok(memory) => memory,
+            Err(err) => {
+                device.destroy_buffer(staging_buffer);
+                return Err(UploadError::VkError(err));
+            }
+        };
+        let mapping = device.bind_buffer_memory(staging_buffer, staging_memory, 0)
+            .map_err(UploadError::VkError)
+            .and_then(|_| {
+                device.map_memory::<u8>(staging_memory, 0, capacity, vk::MemoryMapFlags::empty())
+                    .map_err(UploadError::MapError)
+            });
+        let mapping = match mapping {
+            Ok(mapping) => mapping,
+            Err(err) => {
+                device.destroy_buffer(staging_buffer);
+                device.free_memory(staging_memory);
+                return Err(err);
+            }
+        };
+        let pool_info = vk::CommandPoolCreateInfo {
+            s_type: vk::StructureType::CommandPoolCreateInfo,
+            p_next: ptr::null(),
+            flags: vk::COMMAND_POOL_CREATE_TRANSIENT_BIT |
+                   vk::COMMAND_POOL_CREATE_RESET_COMMAND_BUFFER_BIT,
+            queue_family_index,
+        };
+        let command_pool = match device.create_command_pool(&pool_info) {
+            Ok(pool) => pool,
+            Err(err) => {
+                drop(mapping);
+                device.destroy_buffer(staging_buffer);
+                device.free_memory(staging_memory);
+                return Err(UploadError::VkError(err));
+            }
+        };
+        // 48 is a multiple of every texel block size (1, 2, 3, 4, 6, 8, 12 and 16 bytes), which
+        // `buffer_offset` of a buffer to image copy has to be aligned to.
+        let image_alignment = lcm(48, device.limits().optimal_buffer_copy_offset_alignment);
+        Ok(UploadManager {
+            device,
+            queue,
+            command_pool,
+            staging_buffer,
+            staging_memory,
+            mapping: Some(mapping),
+            coherent: memory_type.property_flags.subset(vk::MEMORY_PROPERTY_HOST_COHERENT_BIT),
+            image_alignment,
+            capacity,
+            head: 0,
+            tail: 0,
+            pending_start: None,
+            buffer_copies: Vec::new(),
+            image_uploads: Vec::new(),
+            in_flight: VecDeque::new(),
+            free_fences: Vec::new(),
+            free_command_buffers: Vec::new(),
+            next_id: 1,
+        })
+    }
+
+    /// Copies `data` to `buffer` at `offset` bytes, the buffer needs
+    /// `BUFFER_USAGE_TRANSFER_DST_BIT`.
+    pub fn upload_buffer<T: Copy>(&mut self,
+                                  buffer: vk::Buffer,
+                                  offset: vk::DeviceSize,
+                                  data: &[T])
+                                  -> Result<(), UploadError> {
+        let bytes = as_bytes(data);
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let src_offset = self.write(bytes, 4)?;
+        self.buffer_copies.push((buffer,
+                                 vk::BufferCopy {
+                                     src_offset,
+                                     dst_offset: offset,
+                                     size: bytes.len() as vk::DeviceSize,
+                                 }));
+        Ok(())
+    }
+
+    /// Copies tightly packed texels to a region of `image`, which needs
+    /// `IMAGE_USAGE_TRANSFER_DST_BIT`. The image is transitioned from `old_layout` to
+    /// `TransferDstOptimal` for the copy and to `new_layout` afterwards.
+    #[allow(clippy::too_many_arguments)]
+    pub fn upload_image<T: Copy>(&mut self,
+                                 image: vk::Image,
+                                 data: &[T],
+                                 subresource: vk::ImageSubresourceLayers,
+                                 offset: vk::Offset3D,
+                                 extent: vk::Extent3D,
+                                 old_layout: vk::ImageLayout,
+                                 new_layout: vk::ImageLayout)
+                                 -> Result<(), UploadError> {
+        let bytes = as_bytes(data);
+        let alignment = self.image_alignment;
+        let buffer_offset = self.write(bytes, alignment)?;
+        self.image_uploads.push(ImageUpload {
+            image,
+            region: vk::BufferImageCopy {
+                buffer_offset,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_subresource: subresource,
+                image_offset: offset,
+                image_extent: extent,
+            },
+            old_layout,
+            new_layout,
+        });
+        Ok(())
+    }
+
+    /// Submits every upload recorded since the last flush and signals `signal_semaphores` once
+    /// it and every earlier batch have executed. Returns the token of the last batch if nothing
+    /// was recorded.
+    ///
+    /// Waiting for the token on the host does not make the uploads visible to the device.
+    /// Submissions that use the uploaded resources have to wait on one of the semaphores, with
+    /// the stages that use them in `p_wait_dst_stage_mask`. Work recorded after the uploads on
+    /// `queue` itself can instead start with a barrier from `PIPELINE_STAGE_TRANSFER_BIT` and
+    /// `ACCESS_TRANSFER_WRITE_BIT` to the stages and accesses that use the resources.
+    pub fn flush(&mut self,
+                 signal_semaphores: &[vk::Semaphore])
+                 -> Result<UploadToken, UploadError> {
+        if self.pending_start.is_none() {
+            if !signal_semaphores.is_empty() {
+                self.submit(None, signal_semaphores, vk::Fence::null())?;
+            }
+            return Ok(UploadToken(self.next_id - 1));
+        }
+        if !self.coherent {
+            self.mapping.as_ref().unwrap().flush()?;
+        }
+        let command_buffer = match self.free_command_buffers.pop() {
+            Some(command_buffer) => command_buffer,
+            None => {
+                let allocate_info = vk::CommandBufferAllocateInfo {
+                    s_type: vk::StructureType::CommandBufferAllocateInfo,
+                    p_next: ptr::null(),
+                    command_pool: self.command_pool,
+                    level: vk::CommandBufferLevel::Primary,
+                    command_buffer_count: 1,
+                };
+                self.device.allocate_command_buffers(&allocate_info)?[0]
+            }
+        };
+        let fence = match self.free_fences.pop() {
+            Some(fence) => fence,
+            None => {
+                let fence_info = vk::FenceCreateInfo {
+                    s_type: vk::StructureType::FenceCreateInfo,
+                    p_next: ptr::null(),
+                    flags: vk::FenceCreateFlags::empty(),
+                };
+                self.device.create_fence(&fence_info)?
+            }
+        };
+        let recorded = self.record(command_buffer)
+            .and_then(|_| self.submit(Some(command_buffer), signal_semaphores, fence));
+        if let Err(err) = recorded {
+            self.free_command_buffers.push(command_buffer);
+            self.free_fences.push(fence);
+            return Err(UploadError::VkError(err));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.in_flight.push_back(Batch {
+            id,
+            start: self.pending_start.take().unwrap(),
+            fence,
+            command_buffer,
+        });
+        self.buffer_copies.clear();
+        self.image_uploads.clear();
+        Ok(UploadToken(id))
+    }
+
+    /// Returns true if the batch of `token` has finished executing.
+    pub fn is_complete(&mut self, token: UploadToken) -> VkResult<bool> {
+        self.retire()?;
+        Ok(self.in_flight.front().is_none_or(|batch| token.0 < batch.id))
+    }
+
+    /// Blocks until the batch of `token` has finished executing.
+    pub fn wait(&mut self, token: UploadToken) -> VkResult<()> {
+        let fence = self.in_flight
+            .iter()
+            .find(|batch| batch.id == token.0)
+            .map(|batch| batch.fence);
+        if let Some(fence) = fence {
+            self.device.wait_for_fences(&[fence], true, u64::MAX)?;
+        }
+        self.retire()
+    }
+
+    fn submit(&self,
+              command_buffer: Option<vk::CommandBuffer>,
+              signal_semaphores: &[vk::Semaphore],
+              fence: vk::Fence)
+              -> VkResult<()> {
+        let submit_info = vk::SubmitInfo {
+            s_type: vk::StructureType::SubmitInfo,
+            p_next: ptr::null(),
+            wait_semaphore_count: 0,
+            p_wait_semaphores: ptr::null(),
+            p_wait_dst_stage_mask: ptr::null(),
+            command_buffer_count: command_buffer.is_some() as vk::uint32_t,
+            p_command_buffers: command_buffer.as_ref().map_or(ptr::null(), |buffer| buffer),
+            signal_semaphore_count: signal_semaphores.len() as vk::uint32_t,
+            p_signal_semaphores: signal_semaphores.as_ptr(),
+        };
+        self.device.queue_submit(self.queue, &[submit_info], fence)
+    }
+
+    fn record(&self, command_buffer: vk::CommandBuffer) -> VkResult<()> {
+        let begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::CommandBufferBeginInfo,
+            p_next: ptr::null(),
+            flags: vk::COMMAND_BUFFER_USAGE_ONE_TIME_SUBMIT_BIT,
+            p_inheritance_info: ptr::null(),
+        };
+        self.device.begin_command_buffer(command_buffer, &begin_info)?;
+        // Buffer ranges and images whose contents are kept may still be accessed by earlier work
+        // on the queue, which the copies and layout transitions have to wait for.
+        let keeps_image_contents = self.image_uploads
+            .iter()
+            .any(|upload| upload.old_layout != vk::ImageLayout::Undefined);
+        let (src_stages, src_access) = if !self.buffer_copies.is_empty() || keeps_image_contents {
+            (vk::PIPELINE_STAGE_ALL_COMMANDS_BIT, vk::ACCESS_MEMORY_WRITE_BIT)
+        } else {
+            (vk::PIPELINE_STAGE_TOP_OF_PIPE_BIT, vk::AccessFlags::empty())
+        };
+        let memory_barriers = if self.buffer_copies.is_empty() {
+            Vec::new()
+        } else {
+            vec![vk::MemoryBarrier {
+                     s_type: vk::StructureType::MemoryBarrier,
+                     p_next: ptr::null(),
+                     src_access_mask: src_access,
+                     dst_access_mask: vk::ACCESS_TRANSFER_WRITE_BIT,
+                 }]
+        };
+        let to_transfer: Vec<vk::ImageMemoryBarrier> = self.image_uploads
+            .iter()
+            .map(|upload| {
+                image_barrier(upload,
+                              src_access,
+                              vk::ACCESS_TRANSFER_WRITE_BIT,
+                              upload.old_layout,
+                              vk::ImageLayout::TransferDstOptimal)
+            })
+            .collect();
+        self.device.cmd_pipeline_barrier(command_buffer,
+                                         src_stages,
+                                         vk::PIPELINE_STAGE_TRANSFER_BIT,
+                                         vk::DependencyFlags::empty(),
+                                         &memory_barriers,
+                                         &[],
+                                         &to_transfer);
+        for &(buffer, ref copy) in &self.buffer_copies {
+            self.device.cmd_copy_buffer(command_buffer,
+                                        self.staging_buffer,
+                                        buffer,
+                                        slice::from_ref(copy));
+        }
+        if !self.image_uploads.is_empty() {
+            for upload in &self.image_uploads {
+                self.device.cmd_copy_buffer_to_image(command_buffer,
+                                                     self.staging_buffer,
+                                                     upload.image,
+                                                     vk::ImageLayout::TransferDstOptimal,
+                                                     slice::from_ref(&upload.region));
+            }
+            // The layout transition has to happen after the copies. Making the writes visible to
+            // the users of the images is left to the semaphores or barrier described in `flush`.
+            let to_final: Vec<vk::ImageMemoryBarrier> = self.image_uploads
+                .iter()
+                .map(|upload| {
+                    image_barrier(upload,
+                                  vk::ACCESS_TRANSFER_WRITE_BIT,
+                                  vk::AccessFlags::empty(),
+                                  vk::ImageLayout::TransferDstOptimal,
+                                  upload.new_layout)
+                })
+                .collect();
+            self.device.cmd_pipeline_barrier(command_buffer,
+                                             vk::PIPELINE_STAGE_TRANSFER_BIT,
+                                             vk::PIPELINE_STAGE_BOTTOM_OF_PIPE_BIT,
+                                             vk::DependencyFlags::empty(),
+                                             &[],
+                                             &[],
+                                             &to_final);
+        }
+        self.device.end_command_buffer(command_buffer)
+    }
+
+    /// Copies `bytes` into the ring and returns their offset in the staging buffer. Waits for
+    /// older batches, or submits the pending one, if the ring is full.
+    fn write(&mut self,
+             bytes: &[u8],
+             alignment: vk::DeviceSize)
+             -> Result<vk::DeviceSize, UploadError> {
+        let size = bytes.len() as vk::DeviceSize;
+        if size > self.capacity {
+            return Err(UploadError::TooLarge {
+                size,
+                capacity: self.capacity,
+            });
+        }
+        loop {
+            if let Some(offset) = self.try_allocate(size, alignment) {
+                let start = offset as usize;
+                self.mapping.as_mut().unwrap()[start..start + bytes.len()].copy_from_slice(bytes);
+                self.head = offset + size;
+                if self.pending_start.is_none() {
+                    self.pending_start = Some(offset);
+                    self.update_tail();
+                }
+                return Ok(offset);
+            }
+            if self.in_flight.is_empty() {
+                self.flush(&[])?;
+            }
+            let fence = self.in_flight.front().unwrap().fence;
+            self.device.wait_for_fences(&[fence], true, u64::MAX)?;
+            self.retire()?;
+        }
+    }
+
+    fn try_allocate(&mut self,
+                    size: vk::DeviceSize,
+                    alignment: vk::DeviceSize)
+                    -> Option<vk::DeviceSize> {
+        if self.in_flight.is_empty() && self.pending_start.is_none() {
+            self.head = 0;
+            self.tail = 0;
+        }
+        let start = align_up(self.head, alignment);
+        if self.head >= self.tail {
+            if start + size <= self.capacity {
+                return Some(start);
+            }
+            // Wrap around, `head` must never catch up with `tail` or the ring looks empty.
+            if size < self.tail {
+                return Some(0);
+            }
+            None
+        } else if start + size < self.tail {
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    /// Recycles the fences and command buffers of every batch that has finished.
+    fn retire(&mut self) -> VkResult<()> {
+        while let Some(fence) = self.in_flight.front().map(|batch| batch.fence) {
+            if !self.device.get_fence_status(fence)? {
+                break;
+            }
+            self.device.reset_fences(&[fence])?;
+            let batch = self.in_flight.pop_front().unwrap();
+            self.free_fences.push(batch.fence);
+            self.free_command_buffers.push(batch.command_buffer);
+        }
+        self.update_tail();
+        Ok(())
+    }
+
+    fn update_tail(&mut self) {
+        self.tail = match self.in_flight.front() {
+            Some(batch) => batch.start,
+            None => self.pending_start.unwrap_or(self.head),
+        };
+    }
+}
+
+impl<'a> Drop for UploadManager<'a> {
+    /// Waits for every submitted batch, uploads that were never flushed are discarded.
+    fn drop(&mut self) {
+        let fences: Vec<vk::Fence> = self.in_flight.iter().map(|batch| batch.fence).collect();
+        if !fences.is_empty() {
+            let _ = self.device.wait_for_fences(&fences, true, u64::MAX);
+        }
+        for fence in fences.into_iter().chain(self.free_fences.drain(..)) {
+            self.device.destroy_fence(fence);
+        }
+        self.device.destroy_command_pool(self.command_pool);
+        self.mapping.take();
+        self.device.destroy_buffer(self.staging_buffer);
+        self.device.free_memory(self.staging_memory);
+    }
+}
+
+fn image_barrier(upload: &ImageUpload,
+                 src_access_mask: vk::AccessFlags,
+                 dst_access_mask: vk::AccessFlags,
+                 old_layout: vk::ImageLayout,
+                 new_layout: vk::ImageLayout)
+                 -> vk::ImageMemoryBarrier {
+    let subresource = &upload.region.image_subresource;
+    vk::ImageMemoryBarrier {
+        s_type: vk::StructureType::ImageMemoryBarrier,
+        p_next: ptr::null(),
+        src_access_mask,
+        dst_access_mask,
+        old_layout,
+        new_layout,
+        src_queue_family_index: vk::VK_QUEUE_FAMILY_IGNORED,
+        dst_queue_family_index: vk::VK_QUEUE_FAMILY_IGNORED,
+        image: upload.image,
+        subresource_range: vk::ImageSubresourceRange {
+            aspect_mask: subresource.aspect_mask,
+            base_mip_level: subresource.mip_level,
+            level_count: 1,
+            base_array_layer: subresource.base_array_layer,
+            layer_count: subresource.layer_count,
+        },
+    }
+}
+
+fn as_bytes<T: Copy>(data: &[T]) -> &[u8] {
+    unsafe { slice::from_raw_parts(data.as_ptr() as *const u8, mem::size_of_val(data)) }
+}
+
+fn align_up(offset: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    offset.div_ceil(alignment) * alignment
+}
+
+fn lcm(a: vk::DeviceSize, b: vk::DeviceSize) -> vk::DeviceSize {
+    if b == 0 {
+        return a;
+    }
+    let (mut x, mut y) = (a, b);
+    while y != 0 {
+        let t = x % y;
+        x = y;
+        y = t;
+    }
+    a / x * b
+}
@@ -0,0 +1,100 @@
+use std::io::{self, Read};
+
+/// The first word of every SPIR-V module.
+pub const SPIRV_MAGIC: u32 = 0x07230203;
+
+/// The number of words in a SPIR-V module header.
+pub const SPIRV_HEADER_WORDS: usize = 5;
+
+/// Reads a SPIR-V module into words, byte-swapping them if the module was written with the
+/// other endianness. Fails with `InvalidData` if the length is not a multiple of 4, the module
+/// is shorter than its header, or it doesn't start with the SPIR-V magic number.
+pub fn read_spv<R: Read>(mut reader: R) -> io::Result<Vec<u32>> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    if bytes.len() % 4 != 0 {
+        return Err(invalid_data(format!("SPIR-V module is {} bytes long, which is not a \
+                                         multiple of 4",
+                                        bytes.len())));
+    }
+    let mut words: Vec<u32> = bytes.chunks(4)
+        .map(|word| {
+            word[0] as u32 | (word[1] as u32) << 8 | (word[2] as u32) << 16 |
+            (word[3] as u32) << 24
+        })
+        .collect();
+    if words.len() < SPIRV_HEADER_WORDS {
+        return Err(invalid_data(format!("SPIR-V module is {} words long, shorter than its {} \
+                                         word header",
+                                        words.len(),
+                                        SPIRV_HEADER_WORDS)));
+    }
+    if words[0] == SPIRV_MAGIC.swap_bytes() {
+        for word in &mut words {
+            *word = word.swap_bytes();
+        }
+    } else if words[0] != SPIRV_MAGIC {
+        return Err(invalid_data(format!("SPIR-V module starts with {:#010x} instead of the \
+                                         magic number {:#010x}",
+                                        words[0],
+                                        SPIRV_MAGIC)));
+    }
+    Ok(words)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::{read_spv, SPIRV_MAGIC};
+
+    const HEADER: [u32; 5] = [SPIRV_MAGIC, 0x0001_0000, 0, 8, 0];
+
+    fn le_bytes(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_le_bytes().to_vec()).collect()
+    }
+
+    fn be_bytes(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect()
+    }
+
+    fn assert_invalid_data(result: io::Result<Vec<u32>>) {
+        match result {
+            Err(ref error) if error.kind() == io::ErrorKind::InvalidData => {}
+            other => panic!("expected InvalidData, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn little_endian_modules_are_read_as_is() {
+        assert_eq!(read_spv(&le_bytes(&HEADER)[..]).unwrap(), HEADER.to_vec());
+    }
+
+    #[test]
+    fn byte_swapped_modules_are_swapped_back() {
+        assert_eq!(read_spv(&be_bytes(&HEADER)[..]).unwrap(), HEADER.to_vec());
+    }
+
+    #[test]
+    fn bad_magic_numbers_are_rejected() {
+        let mut words = HEADER;
+        words[0] = 0xdead_beef;
+        assert_invalid_data(read_spv(&le_bytes(&words)[..]));
+    }
+
+    #[test]
+    fn modules_shorter_than_the_header_are_rejected() {
+        assert_invalid_data(read_spv(&le_bytes(&HEADER[..4])[..]));
+        assert_invalid_data(read_spv(&[][..]));
+    }
+
+    #[test]
+    fn lengths_that_are_not_a_multiple_of_4_are_rejected() {
+        let mut bytes = le_bytes(&HEADER);
+        bytes.push(0);
+        assert_invalid_data(read_spv(&bytes[..]));
+    }
+}
@@ -2656,6 +2656,7 @@ pub mod types {
         ErrorOutOfDateKhr = -1000001004,
         ErrorIncompatibleDisplayKhr = -1000003001,
         ErrorValidationFailedExt = -1000011001,
+        ErrorOutOfPoolMemoryKhr = -1000069000,
     }
 
     #[repr(C)]